@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use super::{NWScriptType, Routine};
+use engine::Engine;
+use vm::{Host, Value, OBJECT_INVALID};
+
+
+/// A small seeded random number generator (xorshift64*), so scripts that roll dice run the same
+/// way every time.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+  pub fn new(seed: u64) -> Rng {
+    Rng(if seed == 0 { 0x9E3779B97F4A7C15 } else { seed })
+  }
+
+  pub fn next(&mut self) -> u32 {
+    self.0 ^= self.0 >> 12;
+    self.0 ^= self.0 << 25;
+    self.0 ^= self.0 >> 27;
+    (self.0.wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
+  }
+
+  /// A number from 0 to n - 1, or 0 if n isn't positive, like the engine's `Random`.
+  pub fn below(&mut self, n: i32) -> i32 {
+    if n <= 0 { 0 } else { (self.next() % n as u32) as i32 }
+  }
+}
+
+/// An engine routine implemented in Rust. Arguments come first argument first.
+pub trait Action {
+  fn call(&mut self, args: Vec<Value>, rng: &mut Rng) -> Result<Value, String>;
+}
+
+impl<F> Action for F where F: FnMut(Vec<Value>, &mut Rng) -> Result<Value, String> {
+  fn call(&mut self, args: Vec<Value>, rng: &mut Rng) -> Result<Value, String> {
+    self(args, rng)
+  }
+}
+
+/// Engine routines by routine code, for running scripts with the VM. Routines that haven't
+/// been registered are logged and return a default value for their return type.
+pub struct Registry {
+  actions: HashMap<u16, Box<Action>>,
+  defaults: Vec<(NWScriptType, Value)>,
+  engine: Engine,
+  pub rng: Rng,
+  pub log: Vec<String> // unregistered calls, as `Name(args) -> result`
+}
+
+impl Registry {
+  pub fn new(engine: Engine, seed: u64) -> Registry {
+    Registry { actions: HashMap::new(), defaults: vec!(), engine: engine, rng: Rng::new(seed),
+               log: vec!() }
+  }
+
+  /// A registry with every routine in `routines` that the stub library implements.
+  pub fn with_library(engine: Engine, seed: u64, routines: &HashMap<u16, Routine>) -> Registry {
+    let mut registry = Registry::new(engine, seed);
+    for r in routines.values() {
+      if let Some(&(_, f)) = LIBRARY.iter().find(|&&(name, _)| name == r.name) {
+        registry.register(r.code, Box::new(f));
+      }
+    }
+    registry
+  }
+
+  pub fn engine(&self) -> Engine {
+    self.engine
+  }
+
+  pub fn register(&mut self, code: u16, action: Box<Action>) {
+    self.actions.insert(code, action);
+  }
+
+  /// Change what unregistered routines returning `t` give back.
+  pub fn set_default(&mut self, t: NWScriptType, value: Value) {
+    self.defaults.retain(|d| d.0 != t);
+    self.defaults.push((t, value));
+  }
+
+  fn default(&self, t: &NWScriptType) -> Result<Value, String> {
+    if let Some(&(_, ref v)) = self.defaults.iter().find(|d| d.0 == *t) {
+      return Ok(v.clone());
+    }
+    Ok(match *t {
+      NWScriptType::Void => Value::Void,
+      NWScriptType::Int | NWScriptType::Any => Value::Int(0),
+      NWScriptType::Float => Value::Float(0.0),
+      NWScriptType::String | NWScriptType::Resource => Value::String(String::new()),
+      NWScriptType::Object | NWScriptType::Player => Value::Object(OBJECT_INVALID),
+      NWScriptType::Vector => Value::Vector(0.0, 0.0, 0.0),
+      ref t => {
+        // Engine structures take their type code from the engine's structure order
+        let name = t.to_string();
+        match self.engine.structures().iter().position(|s| s.0.to_lowercase() == name) {
+          Some(n) => Value::Structure(0x10 + n as u8, 0),
+          None => return Err(format!("no default {} for the {} engine", t, self.engine))
+        }
+      }
+    })
+  }
+}
+
+impl Host for Registry {
+  fn action(&mut self, routine: &Routine, args: Vec<Value>) -> Result<Value, String> {
+    if let Some(action) = self.actions.get_mut(&routine.code) {
+      return action.call(args, &mut self.rng);
+    }
+    let result = try!(self.default(&routine.return_type));
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    self.log.push(format!("{}({}) -> {}", routine.name, args.join(", "), result));
+    Ok(result)
+  }
+}
+
+fn int(args: &[Value], n: usize) -> Result<i32, String> {
+  match args.get(n) {
+    Some(&Value::Int(i)) => Ok(i),
+    Some(v) => Err(format!("argument {} should be an int, not {}", n + 1, v)),
+    None => Err(format!("missing argument {}", n + 1))
+  }
+}
+
+fn float(args: &[Value], n: usize) -> Result<f32, String> {
+  match args.get(n) {
+    Some(&Value::Float(f)) => Ok(f),
+    Some(v) => Err(format!("argument {} should be a float, not {}", n + 1, v)),
+    None => Err(format!("missing argument {}", n + 1))
+  }
+}
+
+fn string(args: &[Value], n: usize) -> Result<&str, String> {
+  match args.get(n) {
+    Some(&Value::String(ref s)) => Ok(s),
+    Some(v) => Err(format!("argument {} should be a string, not {}", n + 1, v)),
+    None => Err(format!("missing argument {}", n + 1))
+  }
+}
+
+fn vector(args: &[Value], n: usize) -> Result<(f32, f32, f32), String> {
+  match args.get(n) {
+    Some(&Value::Vector(x, y, z)) => Ok((x, y, z)),
+    Some(v) => Err(format!("argument {} should be a vector, not {}", n + 1, v)),
+    None => Err(format!("missing argument {}", n + 1))
+  }
+}
+
+// Characters from `start`, at most `count` of them, treating negative numbers as zero
+fn substring(s: &str, start: i32, count: i32) -> String {
+  s.chars().skip(start.max(0) as usize).take(count.max(0) as usize).collect()
+}
+
+// More dice than any script really rolls, low enough that the total can't overflow
+const MAX_DICE: i32 = 10000;
+
+fn dice(sides: i32, args: &[Value], rng: &mut Rng) -> Result<Value, String> {
+  let n = try!(int(args, 0)).max(1).min(MAX_DICE);
+  Ok(Value::Int((0..n).map(|_| rng.below(sides) + 1).sum()))
+}
+
+type LibraryFn = fn(Vec<Value>, &mut Rng) -> Result<Value, String>;
+
+// The stub library: routines that only depend on their arguments, by their NWN names
+const LIBRARY: &'static [(&'static str, LibraryFn)] = &[
+  ("Random", |a, rng| Ok(Value::Int(rng.below(try!(int(&a, 0)))))),
+  ("d2", |a, rng| dice(2, &a, rng)),
+  ("d3", |a, rng| dice(3, &a, rng)),
+  ("d4", |a, rng| dice(4, &a, rng)),
+  ("d6", |a, rng| dice(6, &a, rng)),
+  ("d8", |a, rng| dice(8, &a, rng)),
+  ("d10", |a, rng| dice(10, &a, rng)),
+  ("d12", |a, rng| dice(12, &a, rng)),
+  ("d20", |a, rng| dice(20, &a, rng)),
+  ("d100", |a, rng| dice(100, &a, rng)),
+
+  ("abs", |a, _| Ok(Value::Int(try!(int(&a, 0)).wrapping_abs()))),
+  ("fabs", |a, _| Ok(Value::Float(try!(float(&a, 0)).abs()))),
+  ("sqrt", |a, _| Ok(Value::Float(try!(float(&a, 0)).max(0.0).sqrt()))),
+  ("pow", |a, _| Ok(Value::Float(try!(float(&a, 0)).powf(try!(float(&a, 1)))))),
+  ("log", |a, _| Ok(Value::Float(try!(float(&a, 0)).ln()))),
+  ("cos", |a, _| Ok(Value::Float(try!(float(&a, 0)).to_radians().cos()))),
+  ("sin", |a, _| Ok(Value::Float(try!(float(&a, 0)).to_radians().sin()))),
+  ("tan", |a, _| Ok(Value::Float(try!(float(&a, 0)).to_radians().tan()))),
+  ("acos", |a, _| Ok(Value::Float(try!(float(&a, 0)).acos().to_degrees()))),
+  ("asin", |a, _| Ok(Value::Float(try!(float(&a, 0)).asin().to_degrees()))),
+  ("atan", |a, _| Ok(Value::Float(try!(float(&a, 0)).atan().to_degrees()))),
+
+  ("IntToFloat", |a, _| Ok(Value::Float(try!(int(&a, 0)) as f32))),
+  ("FloatToInt", |a, _| Ok(Value::Int(try!(float(&a, 0)) as i32))),
+  ("IntToString", |a, _| Ok(Value::String(try!(int(&a, 0)).to_string()))),
+  ("IntToHexString", |a, _| Ok(Value::String(format!("0x{:08x}", try!(int(&a, 0)))))),
+  ("FloatToString", |a, _| {
+    let f = try!(float(&a, 0));
+    let width = int(&a, 1).unwrap_or(18).max(0) as usize;
+    let decimals = int(&a, 2).unwrap_or(9).max(0) as usize;
+    Ok(Value::String(format!("{:>1$.2$}", f, width, decimals)))
+  }),
+  ("StringToInt", |a, _| Ok(Value::Int(try!(string(&a, 0)).trim().parse().unwrap_or(0)))),
+  ("StringToFloat", |a, _| Ok(Value::Float(try!(string(&a, 0)).trim().parse().unwrap_or(0.0)))),
+
+  ("GetStringLength", |a, _| Ok(Value::Int(try!(string(&a, 0)).chars().count() as i32))),
+  ("GetStringUpperCase", |a, _| Ok(Value::String(try!(string(&a, 0)).to_uppercase()))),
+  ("GetStringLowerCase", |a, _| Ok(Value::String(try!(string(&a, 0)).to_lowercase()))),
+  ("GetStringLeft", |a, _| Ok(Value::String(substring(try!(string(&a, 0)), 0,
+                                                       try!(int(&a, 1)))))),
+  ("GetStringRight", |a, _| {
+    let s = try!(string(&a, 0));
+    let n = try!(int(&a, 1)).max(0);
+    Ok(Value::String(substring(s, s.chars().count() as i32 - n, n)))
+  }),
+  ("GetSubString", |a, _| Ok(Value::String(substring(try!(string(&a, 0)), try!(int(&a, 1)),
+                                                      try!(int(&a, 2)))))),
+  ("FindSubString", |a, _| {
+    let s = try!(string(&a, 0));
+    let start = int(&a, 2).unwrap_or(0).max(0) as usize;
+    let rest: String = s.chars().skip(start).collect();
+    Ok(Value::Int(match rest.find(try!(string(&a, 1))) {
+      Some(i) => (start + rest[..i].chars().count()) as i32,
+      None => -1
+    }))
+  }),
+
+  ("Vector", |a, _| Ok(Value::Vector(float(&a, 0).unwrap_or(0.0), float(&a, 1).unwrap_or(0.0),
+                                     float(&a, 2).unwrap_or(0.0)))),
+  ("VectorMagnitude", |a, _| {
+    let (x, y, z) = try!(vector(&a, 0));
+    Ok(Value::Float((x * x + y * y + z * z).sqrt()))
+  }),
+  ("VectorNormalize", |a, _| {
+    let (x, y, z) = try!(vector(&a, 0));
+    let m = (x * x + y * y + z * z).sqrt();
+    Ok(if m == 0.0 { Value::Vector(0.0, 0.0, 0.0) } else { Value::Vector(x / m, y / m, z / m) })
+  }),
+  ("AngleToVector", |a, _| {
+    let r = try!(float(&a, 0)).to_radians();
+    Ok(Value::Vector(r.cos(), r.sin(), 0.0))
+  }),
+  ("VectorToAngle", |a, _| {
+    let (x, y, _) = try!(vector(&a, 0));
+    let angle = y.atan2(x).to_degrees();
+    Ok(Value::Float(if angle < 0.0 { angle + 360.0 } else { angle }))
+  })
+];
+
+#[cfg(test)]
+mod actions_tests {
+  use std::collections::HashMap;
+  use engine::Engine;
+  use types::NWScriptType;
+  use vm::{Host, Value};
+  use test_routine as routine;
+  use super::Registry;
+
+  #[test]
+  fn library() {
+    let mut routines = HashMap::new();
+    routines.insert(0, routine(0, "Random", NWScriptType::Int, vec!(NWScriptType::Int)));
+    routines.insert(1, routine(1, "GetSubString", NWScriptType::String,
+                               vec!(NWScriptType::String, NWScriptType::Int, NWScriptType::Int)));
+    routines.insert(2, routine(2, "VectorMagnitude", NWScriptType::Float,
+                               vec!(NWScriptType::Vector)));
+    routines.insert(3, routine(3, "d100", NWScriptType::Int, vec!(NWScriptType::Int)));
+    let mut registry = Registry::with_library(Engine::NWN1, 1, &routines);
+
+    let rolls: Vec<Value> = (0..3)
+      .map(|_| registry.action(&routines[&0], vec!(Value::Int(100))).unwrap())
+      .collect();
+    let mut again = Registry::with_library(Engine::NWN1, 1, &routines);
+    assert_eq!(again.action(&routines[&0], vec!(Value::Int(100))).unwrap(), rolls[0]);
+
+    let args = vec!(Value::String("Hello world".to_string()), Value::Int(6), Value::Int(3));
+    assert_eq!(registry.action(&routines[&1], args).unwrap(), Value::String("wor".to_string()));
+    let args = vec!(Value::Vector(3.0, 0.0, 4.0));
+    assert_eq!(registry.action(&routines[&2], args).unwrap(), Value::Float(5.0));
+    match registry.action(&routines[&3], vec!(Value::Int(50000000))).unwrap() {
+      Value::Int(n) => assert!(n >= 10000 && n <= 1000000),
+      v => panic!("expected an int, got {}", v)
+    }
+    assert!(registry.log.is_empty());
+  }
+
+  #[test]
+  fn unregistered() {
+    let effect = routine(7, "EffectHeal", NWScriptType::Effect, vec!(NWScriptType::Int));
+    let print = routine(8, "PrintInteger", NWScriptType::Int, vec!(NWScriptType::Int));
+    let mut registry = Registry::new(Engine::NWN1, 0);
+    registry.set_default(NWScriptType::Int, Value::Int(-1));
+
+    assert_eq!(registry.action(&effect, vec!(Value::Int(5))).unwrap(), Value::Structure(0x10, 0));
+    assert_eq!(registry.action(&print, vec!(Value::Int(5))).unwrap(), Value::Int(-1));
+    assert_eq!(registry.log, vec!("EffectHeal(5) -> structure 0x10 0x0", "PrintInteger(5) -> -1"));
+    assert!(Registry::new(Engine::Generic, 0).action(&effect, vec!()).is_err());
+  }
+}
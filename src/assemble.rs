@@ -1,26 +1,97 @@
-use std;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufWriter;
 //use std::io::prelude::*;
 use std::io;
 use std::num;
-use std::io::{BufRead, Write, ErrorKind, Seek, SeekFrom};
+use std::io::{BufRead, Write};
 use byteorder;
 use byteorder::{BigEndian, WriteBytesExt};
 //use std::io::error::Error;
 //use std::string::String;
 
+use std::cmp;
+use std::fmt;
+use std::iter::repeat;
 use std::str::FromStr;
 use std::error::Error;
 
 use super::Routine;
-use opcodes::{Opcode, NWType, get_nwtypes, Operand, OpcodeE, NWTypeE};
+use engine::Engine;
+use opcodes::{Opcode, NWType, Operand, OpcodeE, NWTypeE};
+use disassemble::HEADER_BYTES;
+use io_utils::{encode_char, Encoding};
 
 #[derive(Debug)]
 pub enum AssemblyError {
-  ParseError(String), // TODO line count? Also probably could be str instead of String?
-  IOError(io::Error)
+  ParseError(String), // TODO probably could be str instead of String?
+  IOError(io::Error),
+  SourceError(Location, Box<AssemblyError>)
+}
+
+/// The position of an assembly error in the listing, for reporting.
+#[derive(Debug, Clone)]
+pub struct Location {
+  pub line: usize, // 1-based
+  pub column: usize, // 1-based, in chars
+  pub token: String,
+  pub source: String
+}
+
+impl fmt::Display for Location {
+  /// A rustc-style snippet of the offending line with the token underlined.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let gutter = self.line.to_string();
+    let blank = repeat(' ').take(gutter.len()).collect::<String>();
+    // keep tabs so the carets still line up with the source
+    let indent = self.source.chars().take(self.column - 1)
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect::<String>();
+    let carets = repeat('^').take(cmp::max(1, self.token.chars().count())).collect::<String>();
+    try!(writeln!(f, "{} |", blank));
+    try!(writeln!(f, "{} | {}", gutter, self.source));
+    write!(f, "{} | {}{}", blank, indent, carets)
+  }
+}
+
+impl AssemblyError {
+  /// Where in the listing the error happened, if it came from a particular line.
+  pub fn location(&self) -> Option<&Location> {
+    match *self {
+      AssemblyError::SourceError(ref loc, _) => Some(loc),
+      _ => None
+    }
+  }
+}
+
+// Tokens are slices of their line, so their column falls out of the pointer difference
+fn location_of(line: &str, line_no: usize, token: &str) -> Location {
+  let start = token.as_ptr() as usize - line.as_ptr() as usize;
+  let start = if start > line.len() { 0 } else { start };
+  Location { line: line_no, column: line[..start].chars().count() + 1,
+             token: token.to_string(), source: line.to_string() }
+}
+
+fn locate(line: &str, line_no: usize, token: &str, e: AssemblyError) -> AssemblyError {
+  match e {
+    AssemblyError::SourceError(..) => e,
+    _ => AssemblyError::SourceError(location_of(line, line_no, token), Box::new(e))
+  }
+}
+
+impl fmt::Display for AssemblyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      AssemblyError::ParseError(ref m) => write!(f, "{}", m),
+      AssemblyError::IOError(ref e) => write!(f, "{}", e),
+      AssemblyError::SourceError(ref loc, ref e) =>
+        write!(f, "{}\n --> {}:{}\n{}", e, loc.line, loc.column, loc)
+    }
+  }
+}
+
+impl Error for AssemblyError {
+  fn description(&self) -> &str {
+    "assembly failed"
+  }
 }
 
 impl From<io::Error> for AssemblyError {
@@ -31,13 +102,13 @@ impl From<io::Error> for AssemblyError {
 
 impl From<num::ParseIntError> for AssemblyError {
   fn from(e: num::ParseIntError) -> Self {
-    AssemblyError::ParseError(e.description().to_string())
+    AssemblyError::ParseError(e.to_string())
   }
 }
 
 impl From<num::ParseFloatError> for AssemblyError {
   fn from(e: num::ParseFloatError) -> Self {
-    AssemblyError::ParseError(e.description().to_string())
+    AssemblyError::ParseError(e.to_string())
   }
 }
 
@@ -46,6 +117,25 @@ pub type AssemblyResult = Result<(), AssemblyError>;
 type OpcodeMap<'a> = HashMap<String, &'a Opcode>;
 type VariantMap<'a> = HashMap<String, (&'a Opcode, Option<&'a NWType>)>;
 type RoutineMap<'a> = HashMap<&'a String, &'a Routine>;
+type LabelMap = HashMap<String, usize>;
+
+// A label operand whose relative offset can't be written until every label has been seen
+struct Fixup {
+  label: String,
+  location: Location,
+  op_start: usize,
+  pos: usize,
+  size: usize
+}
+
+fn is_label(s: &str) -> bool {
+  let mut chars = s.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+    _ => return false
+  }
+  chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
 fn float_str_to_bytes(size: usize, s: &str) -> Result<Vec<u8>, AssemblyError> {
   let mut buf = vec!();
@@ -116,9 +206,57 @@ fn uint_str_to_bytes(size: usize, pfx: Option<&str>,
   Ok(buf)
 }
 
+// Decode a quoted string operand, undoing the escapes that disassembly applies
+fn string_str_to_bytes(s: &str, encoding: Encoding) -> Result<Vec<u8>, AssemblyError> {
+  if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+    return Err(AssemblyError::ParseError(format!("Expected a quoted string, got {}", s)));
+  }
+  let mut buf = vec!();
+  let mut chars = s[1..s.len()-1].chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      match encode_char(c, encoding) {
+        Some(bytes) => buf.extend(bytes),
+        None => {
+          let msg = format!("Character '{}' can't be encoded as {:?}", c, encoding);
+          return Err(AssemblyError::ParseError(msg));
+        }
+      }
+      continue;
+    }
+    match chars.next() {
+      Some('n') => buf.push(b'\n'),
+      Some('r') => buf.push(b'\r'),
+      Some('t') => buf.push(b'\t'),
+      Some('"') => buf.push(b'"'),
+      Some('\\') => buf.push(b'\\'),
+      Some('x') => {
+        let hex: String = chars.by_ref().take(2).collect();
+        match u8::from_str_radix(&hex, 16) {
+          Ok(b) if hex.len() == 2 => buf.push(b),
+          _ => {
+            let msg = format!("Invalid escape \\x{} in string", hex);
+            return Err(AssemblyError::ParseError(msg));
+          }
+        }
+      },
+      Some(c) => {
+        return Err(AssemblyError::ParseError(format!("Unknown escape \\{} in string", c)));
+      },
+      None => return Err(AssemblyError::ParseError("Unterminated escape in string".to_string()))
+    }
+  }
+  if buf.len() > u16::max_value() as usize {
+    let msg = format!("String of {} bytes is too long", buf.len());
+    return Err(AssemblyError::ParseError(msg));
+  }
+  Ok(buf)
+}
+
 // TODO return Vec<u8> instead
 // TODO correctly take size into account and return only that many bytes
-fn parse_arg(o: &Operand, s: &str, routines: &RoutineMap) -> Result<Vec<u8>, AssemblyError> {
+fn parse_arg(o: &Operand, s: &str, routines: &RoutineMap,
+             encoding: Encoding) -> Result<Vec<u8>, AssemblyError> {
   let mut buf = vec!();
   match *o {
     Operand::Object(sz) | Operand::Size(sz) => {
@@ -134,15 +272,13 @@ fn parse_arg(o: &Operand, s: &str, routines: &RoutineMap) -> Result<Vec<u8>, Ass
       let parts: Vec<&str> = s.split('#').collect();
       let name = parts[0];
 
-      print!(" ~{}~", name);
-      match routines.get(&name.to_string()) {
-        Some(rtn) => {
-          print!(" {:#X}", rtn.code);
-        },
-        None => () // TODO throw error if not in #mode
-      }
-
-      if parts.len() == 2 {
+      if parts.len() == 1 {
+        // No explicit code, so the name has to be a known routine
+        match routines.get(&name.to_string()) {
+          Some(rtn) => buf.extend(try!(uint_str_to_bytes(sz, None, 10, &rtn.code.to_string()))),
+          None => return Err(AssemblyError::ParseError(format!("Unknown routine {}", name)))
+        }
+      } else if parts.len() == 2 {
         let explicit = parts[1];
         /*let offset = if explicit.starts_with("0x") { 2 } else { 0 };
         let num = try!(u32::from_str_radix(&explicit[offset..], 16));
@@ -174,18 +310,18 @@ fn parse_arg(o: &Operand, s: &str, routines: &RoutineMap) -> Result<Vec<u8>, Ass
       buf.extend(try!(float_str_to_bytes(sz, s)));
     },
     Operand::String => {
-      let len = s.len() - 2; // TODO clean up this
-      print!(" {:#04X} {}", len, &s[1..s.len()-1]); // TODO process escape characters
-      try!(buf.write_u16::<BigEndian>(len as u16));
-      buf.extend(s[1..s.len()-1].as_bytes())
+      let bytes = try!(string_str_to_bytes(s, encoding));
+      try!(buf.write_u16::<BigEndian>(bytes.len() as u16));
+      buf.extend(bytes)
     },
   }
   Ok(buf)
 }
 
-fn split_line<'a>(line: &'a String) -> Result<Vec<&'a str>, AssemblyError> {
+fn split_line<'a>(line: &'a String, line_no: usize) -> Result<Vec<&'a str>, AssemblyError> {
   let mut result = vec!();
   let mut stack: Vec<char> = vec!();
+  let mut open = 0;
   let mut escape = false;
   let mut start = 0;
 
@@ -199,13 +335,13 @@ fn split_line<'a>(line: &'a String) -> Result<Vec<&'a str>, AssemblyError> {
       continue;
     }
     match c {
-      // will need an output pass to substitute escape characters and calculate right length
       '\\' => { escape = true; },
       '\"' => { // using ends_with means putting c in a temp vector :C
         if stack.len() > 0 && stack[stack.len()-1] == c {
           stack.pop();
         } else {
           stack.push(c);
+          open = n;
           // TODO strip ""
           // TODO make sure a string is a token?
           // TODO deal with r""?
@@ -225,21 +361,41 @@ fn split_line<'a>(line: &'a String) -> Result<Vec<&'a str>, AssemblyError> {
     result.push(&line[start..]);
   }
   if stack.len() > 0 {
-    Err(AssemblyError::ParseError(format!("Line with unclosed delimiter: {}", line).to_string()))
+    let e = AssemblyError::ParseError("Unclosed delimiter".to_string());
+    Err(locate(line, line_no, &line[open..], e))
   } else {
     Ok(result)
   }
 }
 
-// TODO return a Vec<u8> or something
-// TODO assert first opcode is T
-fn assemble_line<T: Write>(line: &String,
-                           output: &mut T,
-                           opcodes: &OpcodeMap,
-                           variants: &VariantMap,
-                           routines: &RoutineMap) -> AssemblyResult {
+fn assemble_line(line: &String,
+                 line_no: usize,
+                 output: &mut Vec<u8>,
+                 labels: &mut LabelMap,
+                 fixups: &mut Vec<Fixup>,
+                 opcodes: &OpcodeMap,
+                 variants: &VariantMap,
+                 routines: &RoutineMap,
+                 encoding: Encoding) -> AssemblyResult {
+
+  let mut parts = try!(split_line(line, line_no));
+  let op_start = HEADER_BYTES + output.len();
+  let at = |token: &str, e: AssemblyError| locate(line, line_no, token, e);
+
+  // A label definition names the offset of the next instruction
+  if parts.len() > 0 && parts[0].ends_with(':') {
+    let label = &parts[0][..parts[0].len()-1];
+    if !is_label(label) {
+      let e = AssemblyError::ParseError(format!("Invalid label name \"{}\"", label));
+      return Err(at(parts[0], e));
+    }
+    if labels.insert(label.to_string(), op_start).is_some() {
+      let e = AssemblyError::ParseError(format!("Label {} defined more than once", label));
+      return Err(at(parts[0], e));
+    }
+    parts.remove(0);
+  }
 
-  let parts = try!(split_line(line));
   if parts.len() == 0 {
     return Ok(()); // skip
   }
@@ -258,14 +414,15 @@ fn assemble_line<T: Write>(line: &String,
         Some(t) => { (op, Some(t.code as u8)) },
         // irregular variant, type requires additional specifier
         None if parts.len() > 1 => match op.types {
-          Some(ref optypes) => match optypes.get(try!(u8::from_str(parts[1])) as usize) {
-            Some(tt) => {
+          Some(ref optypes) => match try!(uint_str_to_bytes(1, Some("0x"), 16, parts[1])
+                                          .map_err(|e| at(parts[1], e))) {
+            ref tt if optypes.contains(&tt[0]) => {
               tokens += 1;
-              (op, Some(*tt))
+              (op, Some(tt[0]))
             },
-            None => {
+            _ => {
               let e = format!("Opcode {} with illegal or unknown type specifier", op.code);
-              return Err(AssemblyError::ParseError(e.to_string()));
+              return Err(at(parts[1], AssemblyError::ParseError(e.to_string())));
             }
           },
           // No types expected for variant - can this even happen? indicative of bad data design?
@@ -274,7 +431,7 @@ fn assemble_line<T: Write>(line: &String,
         // No name variant and no explicit type, so fail
         None => {
           let e = format!("Opcode with no type specifier: {}", op.code);
-          return Err(AssemblyError::ParseError(e.to_string()));
+          return Err(at(parts[0], AssemblyError::ParseError(e.to_string())));
         }
       }
     },
@@ -291,7 +448,7 @@ fn assemble_line<T: Write>(line: &String,
                 1 => (*op, Some(types[0])),
                 _ => { // ambiguous
                   let msg = format!("Opcode definition error: {} has more than one type", op.code);
-                  return Err(AssemblyError::IOError(io::Error::new(ErrorKind::InvalidInput, msg)))
+                  return Err(AssemblyError::ParseError(msg))
                 }
               }
             },
@@ -300,18 +457,12 @@ fn assemble_line<T: Write>(line: &String,
           }
         },
         None => {
-        let e_str = format!("Expected valid opcode string, got \"{}\"", name);
-          return Err(AssemblyError::IOError(io::Error::new(ErrorKind::InvalidInput, e_str)))
+          let e_str = format!("Expected valid opcode string, got \"{}\"", name);
+          return Err(at(parts[0], AssemblyError::ParseError(e_str)))
         }
       }
     }
   };
-  let real_t = match t_byte {
-    None => "     ".to_string(),
-    Some(b) => format!("{:#04X} ", b)
-  };
-  print!("{:#04X} ({})\t{}({:?})", op.code, op.code, real_t, t_byte);
-
   let mut buf = vec!();
   try!(buf.write_u8(op.code as u8));
   match t_byte {
@@ -345,26 +496,30 @@ fn assemble_line<T: Write>(line: &String,
         None => {
           // Not sure if this is actually an error... but there are no opcodes that trigger this
           let e_str = format!("Opcode {} has no arguments for type {:#04X}", op.code, t_byte);
-          return Err(AssemblyError::IOError(io::Error::new(ErrorKind::InvalidInput, e_str)))
+          return Err(AssemblyError::ParseError(e_str))
         }
       },
       None => if op.code == OpcodeE::T { // hack for T
         args.get(&(0x00 as u8)).unwrap().iter().map(|c| c).collect()
       } else {
-        println!("");
         return Ok(())
       }
     },
-    None => {
-      println!("");
-      return Ok(())
-    }
+    None => return Ok(())
   };
 
+  // T's operand is always recalculated, so it may be left out
+  if op.code == OpcodeE::T && parts.len() == tokens {
+    try!(output.write_u32::<BigEndian>(0));
+    return Ok(())
+  }
+
   if args.len() + tokens != parts.len() {
     let e_str = format!("Opcode {} expects {} args, got {}", op.code, args.len(),
                         parts.len() - tokens);
-    return Err(AssemblyError::ParseError(e_str))
+    // point at the first surplus argument, or at the opcode if some are missing
+    let token = parts.get(args.len() + tokens).unwrap_or(&parts[0]);
+    return Err(at(token, AssemblyError::ParseError(e_str)))
   }
 
   // int types have to_be() for big endian conversion!
@@ -372,7 +527,17 @@ fn assemble_line<T: Write>(line: &String,
   // TODO function?
   for (n, arg) in args.iter().enumerate() {
     let idx = tokens + n;
-    let bytes = try!(parse_arg(*arg, parts[idx], routines));
+    let bytes = match **arg {
+      // Jump targets may be labels, which are patched in once they have all been defined
+      Operand::Offset(sz) if op.code.is_jump() && is_label(parts[idx]) => {
+        fixups.push(Fixup { label: parts[idx].to_string(),
+                            location: location_of(line, line_no, parts[idx]),
+                            op_start: op_start, pos: output.len(), size: sz });
+        vec![0 as u8; sz]
+      },
+      _ => try!(parse_arg(*arg, parts[idx], routines, encoding)
+                .map_err(|e| at(parts[idx], e)))
+    };
     try!(output.write(bytes.as_slice()));
     /*match **arg {
       Operand::Size(sz) if sz == 4 => {
@@ -383,32 +548,25 @@ fn assemble_line<T: Write>(line: &String,
       }
       _ => ()
     }*/
-    print!(" {:?},", arg);
     // see if there's a token, bail if not
     // then match the type of the arg and parse it
-    // TODO string parsing
   }
-  println!("");
 
   Ok(())
 }
 
 // bufread because we want lines
 //#[allow(unused_variables)]
-pub fn assemble<T: BufRead>(input: T,
-                            opcodes: &[Option<Opcode>],
-                            routines: Option<&HashMap<u16, Routine>>,
-                            output_name: Option<&String>) -> AssemblyResult {
-
-  let mut wtr = BufWriter::new(match output_name {
-    Some(path) => Box::new(try!(File::create(path))) as Box<Write>,
-    None => Box::new(std::io::stdout()) as Box<Write>
-  });
-
-  // TODO don't pass output_name as an Option? Generate from input name in main if not given
-  //let mut wtr = BufWriter::new(try!(File::create(path)));
-
-  let nwtypes = get_nwtypes();
+/// Assemble an ox listing read from `input`, writing NCS bytecode to `wtr`. Types are named as
+/// `engine` names them, and string constants are encoded with `encoding`.
+pub fn assemble<T: BufRead, W: Write>(input: T,
+                                      wtr: &mut W,
+                                      opcodes: &[Option<Opcode>],
+                                      routines: Option<&HashMap<u16, Routine>>,
+                                      engine: Engine,
+                                      encoding: Encoding) -> AssemblyResult {
+
+  let nwtypes = engine.nwtypes();
   let mut reverse_opcodes: OpcodeMap = HashMap::new();
   let mut variant_opcodes: VariantMap = HashMap::new();
 
@@ -424,7 +582,7 @@ pub fn assemble<T: BufRead>(input: T,
                 Some(t) => t,
                 None => {
                   let e_str = format!("Variant type {} not found for opcode {}", t, o.code);
-                  return Err(AssemblyError::IOError(io::Error::new(ErrorKind::NotFound, e_str)))
+                  return Err(AssemblyError::ParseError(e_str))
                 }
               };
 
@@ -458,23 +616,108 @@ pub fn assemble<T: BufRead>(input: T,
   }
 
 
-  // Don't forget that you need to emit the header bytes, and T SIZE
-  // T SIZE will needing counting bytes + a rewind.
-  try!(wtr.write(b"NCS V1.0")); // fake header
+  // Everything after the header is buffered so label operands can be patched afterwards
+  let mut buf = vec!();
+  let mut labels: LabelMap = HashMap::new();
+  let mut fixups = vec!();
+  let mut first = None; // where the first instruction is, which has to be T
 
-  for line in input.lines() {
+  for (n, line) in input.lines().enumerate() {
     match line {
-      Ok(s) => try!(assemble_line(&s, &mut wtr,
-                                  &reverse_opcodes, &variant_opcodes, &reverse_routines)),
+      Ok(s) => match assemble_line(&s, n + 1, &mut buf, &mut labels, &mut fixups,
+                                   &reverse_opcodes, &variant_opcodes, &reverse_routines,
+                                   encoding) {
+        Ok(_) => if first.is_none() && !buf.is_empty() {
+          first = Some(location_of(&s, n + 1, s.split_whitespace().next().unwrap_or(&s)));
+        },
+        Err(e) => return Err(locate(&s, n + 1, s.trim(), e))
+      },
       Err(reason) => return Err(AssemblyError::IOError(reason))
     }
   }
 
-  /*let index = try!(wtr.seek(SeekFrom::Start(0)));
-  if index != 0 {
-    println!("wtf");
+  for f in fixups.iter() {
+    let target = match labels.get(&f.label) {
+      Some(t) => *t as i64,
+      None => {
+        let e = AssemblyError::ParseError(format!("Undefined label {}", f.label));
+        return Err(AssemblyError::SourceError(f.location.clone(), Box::new(e)))
+      }
+    };
+    let rel = (target - f.op_start as i64).to_string();
+    let bytes = try!(int_str_to_bytes(f.size, None, 10, &rel)
+                     .map_err(|e| AssemblyError::SourceError(f.location.clone(), Box::new(e))));
+    buf[f.pos..f.pos + f.size].copy_from_slice(bytes.as_slice());
+  }
+
+  // T must come first, and its operand is the size of the whole file including the header
+  if buf.first() != Some(&(OpcodeE::T as u8)) {
+    let e = AssemblyError::ParseError("Expected T as the first opcode".to_string());
+    return Err(match first {
+      Some(location) => AssemblyError::SourceError(location, Box::new(e)),
+      None => e
+    });
   }
-  try!(wtr.write(b"overwritten!"));*/
+  let mut t_size = vec!();
+  try!(t_size.write_u32::<BigEndian>((HEADER_BYTES + buf.len()) as u32));
+  buf[1..5].copy_from_slice(t_size.as_slice());
+
+  try!(wtr.write(b"NCS V1.0")); // fake header
+  try!(wtr.write(buf.as_slice()));
 
   return Ok(())
 }
+
+#[cfg(test)]
+mod assemble_tests {
+  use std::io::Cursor;
+  use engine::Engine;
+  use opcodes::get_opcodes;
+  use io_utils::Encoding;
+  use super::assemble;
+
+  #[test]
+  fn forward_labels() {
+    let src = "T 0x00000000\nJSR main\nRETN\nmain:\nRETN\n";
+    let mut out = vec!();
+    assert!(assemble(Cursor::new(src), &mut out, &get_opcodes(), None, Engine::Generic,
+                     Encoding::Utf8).is_ok());
+    assert_eq!(&out[8..19], b"\x42\x00\x00\x00\x17\x1e\x00\x00\x00\x00\x08");
+  }
+
+  #[test]
+  fn string_escapes() {
+    let src = "T\nCONSTS \"a\\\"b\\n\\x01\"\n";
+    let mut out = vec!();
+    assert!(assemble(Cursor::new(src), &mut out, &get_opcodes(), None, Engine::Generic,
+                     Encoding::Utf8).is_ok());
+    assert_eq!(&out[13..], b"\x04\x05\x00\x05a\"b\n\x01");
+  }
+
+  #[test]
+  fn error_location() {
+    let src = "T\nRETN\n\tCONSTI  12x\n";
+    let mut out = vec!();
+    let e = assemble(Cursor::new(src), &mut out, &get_opcodes(), None, Engine::Generic,
+                     Encoding::Utf8).unwrap_err();
+    let loc = e.location().unwrap();
+    assert_eq!((loc.line, loc.column, loc.token.as_str()), (3, 10, "12x"));
+  }
+
+  #[test]
+  fn missing_t() {
+    let mut out = vec!();
+    let e = assemble(Cursor::new("main:\n  RETN\n"), &mut out, &get_opcodes(), None,
+                     Engine::Generic, Encoding::Utf8).unwrap_err();
+    let loc = e.location().unwrap();
+    assert_eq!((loc.line, loc.column, loc.token.as_str()), (2, 3, "RETN"));
+  }
+
+  #[test]
+  fn undefined_label() {
+    let src = "T 0x00000011\nJMP nowhere\n";
+    let mut out = vec!();
+    assert!(assemble(Cursor::new(src), &mut out, &get_opcodes(), None, Engine::Generic,
+                     Encoding::Utf8).is_err());
+  }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+use super::Routine;
+use disassemble::{format_instruction, jump_target, label_name, DisassemblyError};
+use engine::Engine;
+use io_utils::Encoding;
+use opcodes::{OpPayload, Opcode, OpcodeE};
+
+
+/// Why control moves from one block to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+  Next, // falls through, or returns there from a JSR
+  Jump,
+  Taken, // the branch of a JZ or JNZ
+  NotTaken
+}
+
+/// A straight run of instructions that is only entered at the top and only left at the bottom.
+#[derive(Debug)]
+pub struct Block {
+  pub start: usize,
+  pub end: usize, // offset just past the last instruction
+  pub ops: Vec<usize>, // indexes into the instruction list
+  pub successors: Vec<(Edge, usize)>,
+  pub calls: Vec<usize> // JSR targets
+}
+
+/// The blocks reachable from an entry point without following a JSR. Entries are the start of
+/// the code, every JSR target, and the code saved by each STORE_STATE.
+#[derive(Debug)]
+pub struct Function {
+  pub entry: usize,
+  pub blocks: Vec<usize> // block start offsets, in address order
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+  pub blocks: BTreeMap<usize, Block>,
+  pub functions: Vec<Function>
+}
+
+/// The code a STORE_STATE saves for later, which starts after the JMP that skips it.
+pub fn state_entry(ops: &[OpPayload], n: usize) -> Option<usize> {
+  match (ops[n].op.code, ops.get(n + 1)) {
+    (OpcodeE::STORE_STATE, Some(next)) if next.op.code == OpcodeE::JMP =>
+      Some(next.offset + next.bytes_read),
+    _ => None
+  }
+}
+
+// Does control leave the block after this instruction?
+fn ends_block(code: OpcodeE) -> bool {
+  code.is_jump() || code == OpcodeE::RETN || code == OpcodeE::STORE_STATE
+}
+
+/// Split decoded instructions, as returned by `read_ops`, into basic blocks and group them into
+/// functions. Jumps outside the script are ignored.
+pub fn build_cfg(ops: &[OpPayload]) -> Cfg {
+  let index: HashMap<usize, usize> = ops.iter().enumerate()
+    .map(|(n, o)| (o.offset, n))
+    .collect();
+
+  // Find block leaders and function entries
+  let mut entries = BTreeSet::new();
+  let mut leaders = BTreeSet::new();
+  if let Some(first) = ops.iter().find(|o| o.op.code != OpcodeE::T) {
+    entries.insert(first.offset);
+    leaders.insert(first.offset);
+  }
+  for (n, op) in ops.iter().enumerate().filter(|&(_, o)| o.op.code != OpcodeE::T) {
+    if let Some(target) = jump_target(op).filter(|t| index.contains_key(t)) {
+      leaders.insert(target);
+      if op.op.code == OpcodeE::JSR {
+        entries.insert(target);
+      }
+    }
+    if let Some(entry) = state_entry(ops, n).filter(|t| index.contains_key(t)) {
+      leaders.insert(entry);
+      entries.insert(entry);
+    }
+    if ends_block(op.op.code) {
+      leaders.insert(op.offset + op.bytes_read);
+    }
+  }
+
+  // Cut the instruction stream at each leader
+  let mut blocks = BTreeMap::new();
+  let mut current: Option<Block> = None;
+  for (n, op) in ops.iter().enumerate().filter(|&(_, o)| o.op.code != OpcodeE::T) {
+    if leaders.contains(&op.offset) {
+      if let Some(mut b) = current.take() {
+        b.successors.push((Edge::Next, op.offset));
+        blocks.insert(b.start, b);
+      }
+    }
+    let mut b = current.take().unwrap_or(Block { start: op.offset, end: op.offset, ops: vec!(),
+                                                 successors: vec!(), calls: vec!() });
+    b.ops.push(n);
+    b.end = op.offset + op.bytes_read;
+
+    let target = jump_target(op).filter(|t| index.contains_key(t));
+    let next = Some(b.end).filter(|t| index.contains_key(t));
+    match op.op.code {
+      OpcodeE::JMP => b.successors.extend(target.map(|t| (Edge::Jump, t))),
+      OpcodeE::JZ | OpcodeE::JNZ => {
+        b.successors.extend(target.map(|t| (Edge::Taken, t)));
+        b.successors.extend(next.map(|t| (Edge::NotTaken, t)));
+      },
+      OpcodeE::JSR => {
+        b.calls.extend(target);
+        b.successors.extend(next.map(|t| (Edge::Next, t)));
+      },
+      OpcodeE::STORE_STATE => b.successors.extend(next.map(|t| (Edge::Next, t))),
+      _ => ()
+    }
+    if ends_block(op.op.code) {
+      blocks.insert(b.start, b);
+    } else {
+      current = Some(b);
+    }
+  }
+  if let Some(b) = current {
+    blocks.insert(b.start, b);
+  }
+
+  // Each function is whatever its entry reaches
+  let functions = entries.iter().map(|&entry| {
+    let mut seen = BTreeSet::new();
+    let mut todo = vec!(entry);
+    while let Some(start) = todo.pop() {
+      if blocks.contains_key(&start) && seen.insert(start) {
+        todo.extend(blocks[&start].successors.iter().map(|&(_, t)| t));
+      }
+    }
+    Function { entry: entry, blocks: seen.into_iter().collect() }
+  }).collect();
+
+  Cfg { blocks: blocks, functions: functions }
+}
+
+/// A function found from JSR targets, or the entry stub at the start of the code.
+#[derive(Debug, PartialEq)]
+pub struct Subroutine {
+  pub start: usize,
+  pub end: usize, // just past its last RETN
+  pub calls: usize, // how many JSRs target it
+  pub name: Option<&'static str>
+}
+
+pub fn subroutine_name(offset: usize) -> String {
+  format!("sub_{:08X}", offset)
+}
+
+/// Find subroutines in decoded instructions. Each one runs from its entry to the last RETN
+/// before the next entry. The first JSR in the entry stub calls `main`, or
+/// `StartingConditional` if the stub reserves space for its result first.
+pub fn find_subroutines(ops: &[OpPayload]) -> Vec<Subroutine> {
+  let code: Vec<&OpPayload> = ops.iter().filter(|o| o.op.code != OpcodeE::T).collect();
+  let starts: BTreeSet<usize> = code.iter().map(|o| o.offset).collect();
+  let mut calls: BTreeMap<usize, usize> = BTreeMap::new();
+  if let Some(first) = code.first() {
+    calls.insert(first.offset, 0);
+  }
+  for target in code.iter().filter(|o| o.op.code == OpcodeE::JSR).filter_map(|o| jump_target(o)) {
+    if starts.contains(&target) {
+      *calls.entry(target).or_insert(0) += 1;
+    }
+  }
+
+  let main = code.iter().find(|o| o.op.code == OpcodeE::JSR).and_then(|o| jump_target(o));
+  let conditional = code.first().map_or(false, |o| o.op.code == OpcodeE::RSADD);
+  let entries: Vec<(usize, usize)> = calls.into_iter().collect();
+
+  entries.iter().enumerate().map(|(n, &(start, count))| {
+    let next = entries.get(n + 1).map_or(usize::max_value(), |e| e.0);
+    let end = code.iter()
+      .filter(|o| o.offset >= start && o.offset < next && o.op.code == OpcodeE::RETN)
+      .last()
+      .map_or(next, |o| o.offset + o.bytes_read);
+    let name = if n == 0 {
+      Some("entry point")
+    } else if Some(start) == main {
+      Some(if conditional { "StartingConditional" } else { "main" })
+    } else {
+      None
+    };
+    Subroutine { start: start, end: end, calls: count, name: name }
+  }).collect()
+}
+
+// Escape text for a double quoted DOT label
+fn dot_escape(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Write the graph in Graphviz DOT format, one cluster per function.
+pub fn write_dot<W: Write>(wtr: &mut W,
+                           cfg: &Cfg,
+                           ops: &[OpPayload],
+                           opcodes: &[Option<Opcode>],
+                           routines: &HashMap<u16, Routine>,
+                           engine: Engine,
+                           encoding: Encoding) -> Result<(), DisassemblyError> {
+  try!(writeln!(wtr, "digraph ncs {{"));
+  try!(writeln!(wtr, "  node [shape=box, fontname=\"monospace\"];"));
+
+  for f in cfg.functions.iter() {
+    try!(writeln!(wtr, "  subgraph cluster_{:08X} {{", f.entry));
+    try!(writeln!(wtr, "    label=\"{}\";", subroutine_name(f.entry)));
+    for start in f.blocks.iter() {
+      let b = &cfg.blocks[start];
+      let mut text = String::new();
+      for n in b.ops.iter() {
+        let line = try!(format_instruction(&ops[*n], opcodes, routines, engine, encoding));
+        text.push_str(&format!("{:08X}: {}\\l", ops[*n].offset, dot_escape(line.trim_right())));
+      }
+      // Blocks shared between functions get a node per function
+      try!(writeln!(wtr, "    \"{:08X}_{}\" [label=\"{}:\\l{}\"];",
+                    f.entry, label_name(b.start), label_name(b.start), text));
+    }
+    try!(writeln!(wtr, "  }}"));
+  }
+
+  for f in cfg.functions.iter() {
+    for start in f.blocks.iter() {
+      for &(edge, target) in cfg.blocks[start].successors.iter() {
+        let style = match edge {
+          Edge::Next => "",
+          Edge::Jump => " [style=bold]",
+          Edge::Taken => " [label=\"taken\", color=green]",
+          Edge::NotTaken => " [label=\"not taken\", color=red]"
+        };
+        try!(writeln!(wtr, "  \"{:08X}_{}\" -> \"{:08X}_{}\"{};",
+                      f.entry, label_name(*start), f.entry, label_name(target), style));
+      }
+      for call in cfg.blocks[start].calls.iter() {
+        try!(writeln!(wtr, "  \"{:08X}_{}\" -> \"{:08X}_{}\" [style=dashed];",
+                      f.entry, label_name(*start), call, label_name(*call)));
+      }
+    }
+  }
+  try!(writeln!(wtr, "}}"));
+  Ok(())
+}
+
+#[cfg(test)]
+mod cfg_tests {
+  use std::io::Cursor;
+  use disassemble::read_ops;
+  use opcodes::get_opcodes;
+  use super::{build_cfg, find_subroutines, Edge, Subroutine};
+
+  #[test]
+  fn blocks_and_functions() {
+    // 0D JSR +8, 13 RETN, 15 CONST 1, 1B JZ +8, 21 RETN, 23 RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x25\x1E\x00\x00\x00\x00\x08\x20\x00\
+                 \x04\x03\x00\x00\x00\x01\x1F\x00\x00\x00\x00\x08\x20\x00\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let cfg = build_cfg(&ops);
+
+    let starts: Vec<usize> = cfg.blocks.keys().cloned().collect();
+    assert_eq!(starts, vec!(0x0D, 0x13, 0x15, 0x21, 0x23));
+    assert_eq!(cfg.blocks[&0x0D].calls, vec!(0x15));
+    assert_eq!(cfg.blocks[&0x15].successors, vec!((Edge::Taken, 0x23), (Edge::NotTaken, 0x21)));
+    assert_eq!(cfg.functions.len(), 2);
+    assert_eq!(cfg.functions[0].blocks, vec!(0x0D, 0x13));
+    assert_eq!(cfg.functions[1].blocks, vec!(0x15, 0x21, 0x23));
+  }
+
+  #[test]
+  fn subroutines() {
+    // 0D RSADDI, 0F JSR +8, 15 RETN, 17 RETN, 19 JSR -2, 1F RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x21\x02\x03\x1E\x00\x00\x00\x00\x08\x20\x00\
+                 \x20\x00\x1E\x00\xFF\xFF\xFF\xFE\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let subs = find_subroutines(&ops);
+    assert_eq!(subs, vec!(
+      Subroutine { start: 0x0D, end: 0x17, calls: 0, name: Some("entry point") },
+      Subroutine { start: 0x17, end: 0x21, calls: 2, name: Some("StartingConditional") }
+    ));
+  }
+}
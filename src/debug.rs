@@ -0,0 +1,429 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::{BufRead, Write};
+
+use super::Routine;
+use actions::{Registry, Rng};
+use cfg::{find_subroutines, subroutine_name};
+use disassemble::{format_instruction, jump_target, label_name};
+use io_utils::Encoding;
+use opcodes::{OpPayload, Opcode, OpcodeE};
+use vm::{Value, Vm};
+
+
+const HELP: &'static str = "\
+Commands:
+  s, step              Run one instruction.
+  n, next              Run one instruction, stepping over JSR calls.
+  c, continue          Run until a breakpoint, a watched slot changes or the script ends.
+  b, break WHERE       Stop before the instruction at WHERE: an offset like 0x1D or
+                       0000001D, a label like loc_0000001D or sub_00000015, or main
+                       or StartingConditional.
+  d, delete WHERE      Remove a breakpoint.
+  w, watch SLOT        Stop when a stack slot changes. SLOT is an offset from SP or BP,
+                       e.g. sp-4 or bp-8; a bare number is from SP.
+  u, unwatch SLOT      Stop watching a slot.
+  stack [N]            Show the top N stack values (default 8) with their SP and BP offsets.
+  frame                Show BP, the values below it and the call stack.
+  l, list [N]          Show the next N instructions (default 5).
+  mock NAME VALUE      Make engine routine NAME return VALUE: an int, a float like 1.0, a
+                       \"string\", an object like 0x7F000000, a vector [x, y, z] or void.
+  calls                Show the engine calls made so far that weren't mocked.
+  info                 Show breakpoints and watches.
+  h, help              Show this message.
+  q, quit              Leave the debugger.
+";
+
+/// An interactive debugger over a VM, with stub engine routines from a `Registry`.
+pub struct Debugger<'a, 'b: 'a> {
+  pub vm: Vm<'a, 'b>,
+  pub registry: Registry,
+  ops: &'a [OpPayload<'b>],
+  opcodes: &'a [Option<Opcode>],
+  routines: &'a HashMap<u16, Routine>,
+  encoding: Encoding,
+  labels: HashMap<String, usize>,
+  pub breakpoints: BTreeSet<usize>,
+  pub watches: Vec<(String, usize, Option<Value>)>, // name, stack cell and last value
+  pub steps: usize, // how far `continue` runs before giving up
+  shown: usize // registry log entries already printed
+}
+
+// Parse a value as written after `mock`
+fn parse_value(text: &str) -> Option<Value> {
+  let text = text.trim();
+  if text == "void" {
+    return Some(Value::Void);
+  }
+  if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+    let s = text[1..text.len() - 1].replace("\\\"", "\"").replace("\\n", "\n")
+      .replace("\\\\", "\\");
+    return Some(Value::String(s));
+  }
+  if text.starts_with("0x") {
+    return u32::from_str_radix(&text[2..], 16).ok().map(Value::Object);
+  }
+  if text.starts_with('[') && text.ends_with(']') {
+    let v: Vec<f32> = text[1..text.len() - 1].split(',')
+      .filter_map(|f| f.trim().parse().ok())
+      .collect();
+    return if v.len() == 3 { Some(Value::Vector(v[0], v[1], v[2])) } else { None };
+  }
+  if text.contains('.') {
+    return text.parse().ok().map(Value::Float);
+  }
+  text.parse().ok().map(Value::Int)
+}
+
+impl<'a, 'b> Debugger<'a, 'b> {
+  pub fn new(ops: &'a [OpPayload<'b>],
+             opcodes: &'a [Option<Opcode>],
+             routines: &'a HashMap<u16, Routine>,
+             registry: Registry,
+             encoding: Encoding) -> Debugger<'a, 'b> {
+    let mut labels = HashMap::new();
+    for target in ops.iter().filter_map(|o| jump_target(o)) {
+      labels.insert(label_name(target), target);
+    }
+    for sub in find_subroutines(ops) {
+      labels.insert(subroutine_name(sub.start), sub.start);
+      if let Some(name) = sub.name {
+        labels.insert(name.to_string(), sub.start);
+      }
+    }
+    Debugger { vm: Vm::new(ops, routines, encoding), registry: registry, ops: ops,
+               opcodes: opcodes, routines: routines, encoding: encoding, labels: labels,
+               breakpoints: BTreeSet::new(), watches: vec!(), steps: 1000000, shown: 0 }
+  }
+
+  // An instruction offset from a label, or a hex offset with or without its 0x
+  fn location(&self, text: &str) -> Result<usize, String> {
+    let offset = match self.labels.get(text) {
+      Some(o) => *o,
+      None => {
+        let hex = if text.starts_with("0x") { &text[2..] } else { text };
+        let hex = if text.starts_with("loc_") || text.starts_with("sub_") { &text[4..] }
+                  else { hex };
+        try!(usize::from_str_radix(hex, 16).map_err(|_| format!("no label or offset {}", text)))
+      }
+    };
+    if self.ops.iter().any(|o| o.offset == offset && o.op.code != OpcodeE::T) {
+      Ok(offset)
+    } else {
+      Err(format!("no instruction starts at {:08X}", offset))
+    }
+  }
+
+  // The stack cell a watch refers to, and the name to show for it
+  fn slot(&self, text: &str) -> Result<(String, usize), String> {
+    let (base, name, offset) = if text.starts_with("bp") {
+      (self.vm.bp / 4, "bp", &text[2..])
+    } else if text.starts_with("sp") {
+      (self.vm.stack.len(), "sp", &text[2..])
+    } else {
+      (self.vm.stack.len(), "sp", text)
+    };
+    let n: i64 = try!(offset.trim_left_matches('+').parse().map_err(|_| {
+      format!("bad stack slot {}", text)
+    }));
+    let cell = base as i64 + n / 4;
+    if n % 4 != 0 || cell < 0 || cell >= self.vm.stack.len() as i64 {
+      return Err(format!("{} isn't a slot on the stack", text));
+    }
+    let sign = if n < 0 { "" } else { "+" };
+    Ok((format!("{}{}{}", name, sign, n), cell as usize))
+  }
+
+  fn describe(&self, op: &OpPayload) -> String {
+    let text = format_instruction(op, self.opcodes, self.routines, self.registry.engine(),
+                                  self.encoding)
+      .unwrap_or_else(|e| format!("<{}>", e));
+    let marker = if self.breakpoints.contains(&op.offset) { "*" } else { " " };
+    format!("{}{:08X}  {}", marker, op.offset, text.trim_right())
+  }
+
+  fn show_position<W: Write>(&mut self, wtr: &mut W) -> io::Result<()> {
+    for call in self.registry.log[self.shown..].iter() {
+      try!(writeln!(wtr, "  ; {}", call));
+    }
+    self.shown = self.registry.log.len();
+    if self.vm.halted {
+      let top = self.vm.stack.last().map_or(String::new(), |v| format!(", leaving {}", v));
+      return writeln!(wtr, "script finished{}", top);
+    }
+    match self.vm.current() {
+      Some(op) => writeln!(wtr, "=>{}", self.describe(op)),
+      None => writeln!(wtr, "=> {:08X}  <no instruction>", self.vm.pc)
+    }
+  }
+
+  // Run one instruction, then report any watch it tripped
+  fn step<W: Write>(&mut self, wtr: &mut W) -> io::Result<bool> {
+    if let Err(e) = self.vm.step(&mut self.registry) {
+      try!(writeln!(wtr, "error at {}", e));
+      return Ok(true);
+    }
+    let mut stop = false;
+    for watch in self.watches.iter_mut() {
+      let now = self.vm.stack.get(watch.1).cloned();
+      if now != watch.2 {
+        let show = |v: &Option<Value>| {
+          v.as_ref().map_or("<popped>".to_string(), |v| v.to_string())
+        };
+        try!(writeln!(wtr, "watch {}: {} -> {}", watch.0, show(&watch.2), show(&now)));
+        watch.2 = now;
+        stop = true;
+      }
+    }
+    Ok(stop)
+  }
+
+  // Keep stepping until something stops us. Depth limits the run to returning from a call.
+  fn run<W: Write>(&mut self, wtr: &mut W, depth: Option<usize>) -> io::Result<()> {
+    let mut steps = 0;
+    loop {
+      if try!(self.step(wtr)) || self.vm.halted {
+        break;
+      }
+      if depth.map_or(false, |d| self.vm.calls.len() <= d) {
+        break;
+      }
+      if self.breakpoints.contains(&self.vm.pc) {
+        try!(writeln!(wtr, "breakpoint at {:08X}", self.vm.pc));
+        break;
+      }
+      steps += 1;
+      if steps == self.steps {
+        try!(writeln!(wtr, "still running after {} steps", steps));
+        break;
+      }
+    }
+    self.show_position(wtr)
+  }
+
+  fn show_stack<W: Write>(&self, wtr: &mut W, count: usize) -> io::Result<()> {
+    let len = self.vm.stack.len();
+    let bp = self.vm.bp / 4;
+    try!(writeln!(wtr, "sp={} bp={}", self.vm.sp(), self.vm.bp));
+    for i in (len.saturating_sub(count)..len).rev() {
+      let sp_off = format!("sp{}", (i as i64 - len as i64) * 4);
+      let bp_off = (i as i64 - bp as i64) * 4;
+      let bp_off = if bp_off < 0 { format!("bp{}", bp_off) } else { format!("bp+{}", bp_off) };
+      try!(writeln!(wtr, "  {:<8} {:<8} {}", sp_off, bp_off, self.vm.stack[i]));
+    }
+    Ok(())
+  }
+
+  fn show_frame<W: Write>(&self, wtr: &mut W) -> io::Result<()> {
+    let bp = self.vm.bp / 4;
+    let len = self.vm.stack.len();
+    try!(writeln!(wtr, "bp={}", self.vm.bp));
+    // a broken script can pop below BP, or restore a BP that was never saved
+    if bp > len {
+      try!(writeln!(wtr, "  (cells below bp missing: {})", bp - len));
+    }
+    for i in (0..bp.min(len)).rev() {
+      try!(writeln!(wtr, "  bp{:<6} {}", (i as i64 - bp as i64) * 4, self.vm.stack[i]));
+    }
+    try!(writeln!(wtr, "call stack:"));
+    try!(writeln!(wtr, "  {:08X}", self.vm.pc));
+    for ret in self.vm.calls.iter().rev() {
+      try!(writeln!(wtr, "  {:08X}", ret));
+    }
+    Ok(())
+  }
+
+  /// Carry out one command line. Returns false once the user asks to quit.
+  pub fn command<W: Write>(&mut self, line: &str, wtr: &mut W) -> io::Result<bool> {
+    let mut words = line.split_whitespace();
+    let cmd = match words.next() {
+      Some(c) => c,
+      None => return Ok(true)
+    };
+    let arg = words.next();
+    let count = |default: usize| arg.and_then(|a| a.parse().ok()).unwrap_or(default);
+    let finished = self.vm.halted;
+
+    let result: Result<(), String> = match cmd {
+      "s" | "step" | "n" | "next" | "c" | "continue" if finished =>
+        Err("the script has finished".to_string()),
+      "s" | "step" => {
+        try!(self.step(wtr));
+        try!(self.show_position(wtr));
+        Ok(())
+      },
+      "n" | "next" => {
+        let call = self.vm.current().map_or(false, |o| o.op.code == OpcodeE::JSR);
+        let depth = self.vm.calls.len();
+        if call {
+          try!(self.run(wtr, Some(depth)));
+        } else {
+          try!(self.step(wtr));
+          try!(self.show_position(wtr));
+        }
+        Ok(())
+      },
+      "c" | "continue" => {
+        try!(self.run(wtr, None));
+        Ok(())
+      },
+      "b" | "break" | "d" | "delete" => match arg.ok_or("where?".to_string())
+                                                .and_then(|a| self.location(a)) {
+        Ok(offset) if cmd.starts_with('b') => {
+          self.breakpoints.insert(offset);
+          writeln!(wtr, "breakpoint at {:08X}", offset).map_err(|e| e.to_string())
+        },
+        Ok(offset) => {
+          self.breakpoints.remove(&offset);
+          Ok(())
+        },
+        Err(e) => Err(e)
+      },
+      "w" | "watch" => match arg.ok_or("which slot?".to_string()).and_then(|a| self.slot(a)) {
+        Ok((name, cell)) => {
+          let value = self.vm.stack.get(cell).cloned();
+          try!(writeln!(wtr, "watching {} = {}", name, value.as_ref().unwrap()));
+          self.watches.push((name, cell, value));
+          Ok(())
+        },
+        Err(e) => Err(e)
+      },
+      "u" | "unwatch" => {
+        let before = self.watches.len();
+        self.watches.retain(|w| Some(w.0.as_ref()) != arg);
+        if self.watches.len() == before { Err("no such watch".to_string()) } else { Ok(()) }
+      },
+      "stack" => {
+        try!(self.show_stack(wtr, count(8)));
+        Ok(())
+      },
+      "frame" => {
+        try!(self.show_frame(wtr));
+        Ok(())
+      },
+      "l" | "list" => {
+        let start = self.ops.iter().position(|o| o.offset == self.vm.pc).unwrap_or(0);
+        for op in self.ops[start..].iter().filter(|o| o.op.code != OpcodeE::T).take(count(5)) {
+          try!(writeln!(wtr, "{}", self.describe(op)));
+        }
+        Ok(())
+      },
+      "mock" => {
+        let rest: Vec<&str> = words.collect();
+        let routine = arg.and_then(|a| self.routines.values().find(|r| r.name == a));
+        match (routine, parse_value(&rest.join(" "))) {
+          (None, _) => Err(format!("no routine {}", arg.unwrap_or(""))),
+          (_, None) => Err("what value?".to_string()),
+          (Some(r), Some(value)) => {
+            try!(writeln!(wtr, "{} returns {}", r.name, value));
+            let mocked = move |_: Vec<Value>, _: &mut Rng| -> Result<Value, String> {
+              Ok(value.clone())
+            };
+            self.registry.register(r.code, Box::new(mocked));
+            Ok(())
+          }
+        }
+      },
+      "calls" => {
+        for call in self.registry.log.iter() {
+          try!(writeln!(wtr, "{}", call));
+        }
+        Ok(())
+      },
+      "info" => {
+        for b in self.breakpoints.iter() {
+          try!(writeln!(wtr, "breakpoint {:08X}", b));
+        }
+        for w in self.watches.iter() {
+          try!(writeln!(wtr, "watch {}", w.0));
+        }
+        Ok(())
+      },
+      "h" | "help" => {
+        try!(write!(wtr, "{}", HELP));
+        Ok(())
+      },
+      "q" | "quit" => return Ok(false),
+      _ => Err(format!("unknown command {}, try help", cmd))
+    };
+    if let Err(e) = result {
+      try!(writeln!(wtr, "{}", e));
+    }
+    Ok(true)
+  }
+}
+
+/// Read commands from `rdr` until `quit` or the end of input.
+pub fn repl<R: BufRead, W: Write>(rdr: &mut R,
+                                  wtr: &mut W,
+                                  debugger: &mut Debugger) -> io::Result<()> {
+  try!(debugger.show_position(wtr));
+  loop {
+    try!(write!(wtr, "(ox) "));
+    try!(wtr.flush());
+    let mut line = String::new();
+    if try!(rdr.read_line(&mut line)) == 0 || !try!(debugger.command(&line, wtr)) {
+      return Ok(());
+    }
+  }
+}
+
+#[cfg(test)]
+mod debug_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use actions::Registry;
+  use disassemble::read_ops;
+  use engine::Engine;
+  use io_utils::Encoding;
+  use opcodes::get_opcodes;
+  use super::{repl, Debugger};
+
+  // 0D RSADDI, 0F JSR +8, 15 RETN, 17 CONSTI 7, 1D CPDOWNSP -8 4, 25 MOVSP -4, 2B RETN
+  const SCRIPT: &'static [u8] = b"NCS V1.0\x42\x00\x00\x00\x2D\x02\x03\
+                                  \x1E\x00\x00\x00\x00\x08\x20\x00\x04\x03\x00\x00\x00\x07\
+                                  \x01\x01\xFF\xFF\xFF\xF8\x00\x04\x1B\x00\xFF\xFF\xFF\xFC\x20\x00";
+
+  fn session(commands: &str) -> String {
+    session_on(SCRIPT, commands)
+  }
+
+  fn session_on(script: &[u8], commands: &str) -> String {
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(script), &opcodes).unwrap();
+    let routines = HashMap::new();
+    let registry = Registry::new(Engine::Generic, 0);
+    let mut debugger = Debugger::new(&ops, &opcodes, &routines, registry, Encoding::Utf8);
+    let mut out = vec!();
+    repl(&mut Cursor::new(commands), &mut out, &mut debugger).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn breakpoints_and_stepping() {
+    let out = session("b StartingConditional\nc\ns\nstack\nn\nq\n");
+    assert!(out.contains("breakpoint at 00000017\n"));
+    assert!(out.contains("=>*00000017  CONSTI        7\n"));
+    assert!(out.contains("  sp-4     bp+4     7\n  sp-8     bp+0     0\n"));
+
+    let out = session("s\nn\nframe\nc\n");
+    assert!(out.contains("=> 00000015  RETN\n"));
+    assert!(out.contains("script finished, leaving 7\n"));
+  }
+
+  #[test]
+  fn watches() {
+    let out = session("s\nw sp-4\nc\nbogus\n");
+    assert!(out.contains("watching sp-4 = 0\n"));
+    assert!(out.contains("watch sp-4: 0 -> 7\n=> 00000025  MOVSP"));
+    assert!(out.contains("unknown command bogus"));
+  }
+
+  #[test]
+  fn frame_below_bp() {
+    // SAVEBP, MOVSP -4, RETN
+    let script = b"NCS V1.0\x42\x00\x00\x00\x17\x2A\x00\x1B\x00\xFF\xFF\xFF\xFC\x20\x00";
+    let out = session_on(script, "s\ns\nframe\nq\n");
+    assert!(out.contains("bp=4\n  (cells below bp missing: 1)\ncall stack:\n"));
+  }
+}
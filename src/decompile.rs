@@ -0,0 +1,1227 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use super::{NWScriptType, Routine};
+use cfg::{build_cfg, find_subroutines, state_entry, subroutine_name, Subroutine};
+use disassemble::{jump_target, label_name};
+use engine::Engine;
+use io_utils::{bytes_to_float, bytes_to_int, bytes_to_uint, escape_string, Encoding};
+use opcodes::{OpPayload, OpcodeE, Operand};
+use stack::check_stack;
+use vm::OBJECT_INVALID;
+
+
+const UNARY: u8 = 11;
+const PRIMARY: u8 = 12;
+
+// An expression waiting on the stack to be used
+#[derive(Debug, Clone)]
+struct Expr {
+  text: String,
+  nwtype: NWScriptType,
+  prec: u8,
+  pure: bool // dropping it unused doesn't lose a side effect
+}
+
+impl Expr {
+  fn primary(text: String, nwtype: NWScriptType) -> Expr {
+    Expr { text: text, nwtype: nwtype, prec: PRIMARY, pure: true }
+  }
+
+  // The text to use as an operand of an operator with precedence prec
+  fn operand(&self, prec: u8) -> String {
+    if self.prec < prec { format!("({})", self.text) } else { self.text.clone() }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Slot {
+  Temp(Expr),
+  Var(String, NWScriptType),
+  Return // the caller's slot for the function's result
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  slot: Slot,
+  size: usize
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+  Decl(NWScriptType, String, Option<String>),
+  Assign(String, String),
+  Expr(String),
+  Return(Option<String>),
+  If(String, Vec<Stmt>, Vec<Stmt>),
+  While(String, Vec<Stmt>),
+  For(String, String, String, Vec<Stmt>),
+  Break,
+  Continue,
+  Comment(String)
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+  params: Vec<NWScriptType>,
+  ret: NWScriptType,
+  arg_bytes: usize,
+  ret_bytes: usize
+}
+
+fn prefix(t: &NWScriptType) -> &'static str {
+  match *t {
+    NWScriptType::Int => "n",
+    NWScriptType::Float => "f",
+    NWScriptType::String => "s",
+    NWScriptType::Object => "o",
+    NWScriptType::Vector => "v",
+    NWScriptType::Effect => "e",
+    NWScriptType::Event => "ev",
+    NWScriptType::Location => "l",
+    NWScriptType::Talent => "t",
+    NWScriptType::ItemProperty => "ip",
+    _ => "x"
+  }
+}
+
+// The type a default-sized value of `bytes` bytes most likely has
+fn sized_type(bytes: usize) -> NWScriptType {
+  match bytes {
+    0 => NWScriptType::Void,
+    12 => NWScriptType::Vector,
+    _ => NWScriptType::Int
+  }
+}
+
+fn structure_type(engine: Engine, t: u8) -> NWScriptType {
+  let structures = if engine == Engine::Generic { Engine::NWN1 } else { engine }.structures();
+  match structures.get(t.wrapping_sub(0x10) as usize).map(|s| s.0) {
+    Some("Effect") => NWScriptType::Effect,
+    Some("Event") => NWScriptType::Event,
+    Some("Location") => NWScriptType::Location,
+    Some("Talent") => NWScriptType::Talent,
+    Some("ItemProperty") => NWScriptType::ItemProperty,
+    Some("Command") => NWScriptType::Command,
+    Some("Player") => NWScriptType::Player,
+    _ => NWScriptType::Any
+  }
+}
+
+fn binary_operator(code: OpcodeE) -> Option<(&'static str, u8)> {
+  Some(match code {
+    OpcodeE::LOGORII => ("||", 1),
+    OpcodeE::LOGANDII => ("&&", 2),
+    OpcodeE::INCORII => ("|", 3),
+    OpcodeE::EXCORII => ("^", 4),
+    OpcodeE::BOOLANDII => ("&", 5),
+    OpcodeE::EQUAL => ("==", 6),
+    OpcodeE::NEQUAL => ("!=", 6),
+    OpcodeE::GEQ => (">=", 7),
+    OpcodeE::GT => (">", 7),
+    OpcodeE::LT => ("<", 7),
+    OpcodeE::LEQ => ("<=", 7),
+    OpcodeE::SHLEFTII => ("<<", 8),
+    OpcodeE::SHRIGHTII => (">>", 8),
+    OpcodeE::USHRIGHTII => (">>>", 8),
+    OpcodeE::ADD => ("+", 9),
+    OpcodeE::SUB => ("-", 9),
+    OpcodeE::MUL => ("*", 10),
+    OpcodeE::DIV => ("/", 10),
+    OpcodeE::MODII => ("%", 10),
+    _ => return None
+  })
+}
+
+// The entry holding the byte `offset` bytes from the top of `stack`, and where in it
+fn find(stack: &[Entry], offset: i32) -> Option<(usize, i32)> {
+  let mut top = 0;
+  for i in (0..stack.len()).rev() {
+    let start = top - stack[i].size as i32;
+    if offset >= start && offset < top {
+      return Some((i, offset - start));
+    }
+    top = start;
+  }
+  None
+}
+
+// Remove the declaration an RSADD made for a slot that turned out not to be a variable
+fn forget_decl(out: &mut Vec<Stmt>, name: &str) {
+  let pos = out.iter().rposition(|s| match *s {
+    Stmt::Decl(_, ref n, None) => n == name,
+    _ => false
+  });
+  if let Some(pos) = pos {
+    out.remove(pos);
+  }
+}
+
+fn mentions(text: &str, name: &str) -> bool {
+  text.split(|c: char| !c.is_alphanumeric() && c != '_').any(|w| w == name)
+}
+
+// Turn `i = 0; while (i < n) { ...; i++; }` into a for loop. NWScript can't declare in the
+// initializer, so `int i = 0;` before the loop splits into a declaration and the initializer.
+fn fold_for(stmts: &mut Vec<Stmt>) {
+  for s in stmts.iter_mut() {
+    match *s {
+      Stmt::If(_, ref mut a, ref mut b) => {
+        fold_for(a);
+        fold_for(b);
+      },
+      Stmt::While(_, ref mut b) | Stmt::For(_, _, _, ref mut b) => fold_for(b),
+      _ => ()
+    }
+  }
+  let mut i = 1;
+  while i < stmts.len() {
+    let (v, init) = match stmts[i - 1] {
+      Stmt::Assign(ref v, ref init) | Stmt::Decl(_, ref v, Some(ref init)) =>
+        (v.clone(), init.clone()),
+      _ => {
+        i += 1;
+        continue;
+      }
+    };
+    let folded = match stmts[i] {
+      Stmt::While(ref cond, ref body) if mentions(cond, &v) => {
+        let inc = match body.last() {
+          Some(&Stmt::Assign(ref w, ref e)) if *w == v => Some(format!("{} = {}", w, e)),
+          Some(&Stmt::Expr(ref e)) if *e == format!("{}++", v) || *e == format!("{}--", v) =>
+            Some(e.clone()),
+          _ => None
+        };
+        inc.map(|inc| Stmt::For(format!("{} = {}", v, init), cond.clone(), inc,
+                                body[..body.len() - 1].to_vec()))
+      },
+      _ => None
+    };
+    match folded {
+      Some(f) => {
+        stmts[i] = f;
+        match stmts[i - 1] {
+          Stmt::Decl(_, _, ref mut init) => *init = None,
+          _ => {
+            stmts.remove(i - 1);
+          }
+        }
+      },
+      None => i += 1
+    }
+  }
+}
+
+struct Decompiler<'a, 'b: 'a> {
+  ops: &'a [OpPayload<'b>],
+  index: HashMap<usize, usize>, // instruction offset to position in ops
+  ends: HashMap<usize, usize>, // offset just past an instruction to its position
+  routines: &'a HashMap<u16, Routine>,
+  engine: Engine,
+  encoding: Encoding,
+  names: HashMap<usize, String>,
+  sigs: HashMap<usize, Signature>,
+  observed: HashMap<usize, (Vec<NWScriptType>, Option<NWScriptType>)>, // from call sites
+  globals: Vec<Entry>, // the stack below BP
+  global_decls: Vec<Stmt>,
+
+  // The function being decompiled
+  var_style: &'static str,
+  vars: usize,
+  end: usize, // offset of its last RETN
+  loops: Vec<(usize, usize, usize)>, // continue target, break target and end of the body
+  pending: Option<String>, // the action saved by the last STORE_STATE
+  returned: Option<NWScriptType>
+}
+
+impl<'a, 'b> Decompiler<'a, 'b> {
+  fn new_var(&mut self, t: &NWScriptType) -> String {
+    self.vars += 1;
+    format!("{}{}{}", prefix(t), self.var_style, self.vars)
+  }
+
+  fn literal(&self, op: &OpPayload) -> Result<Expr, String> {
+    for &(arg, ref bytes) in op.args.iter() {
+      let e = match *arg {
+        Operand::Integer(..) => bytes_to_int(bytes).ok()
+          .map(|n| Expr::primary(n.to_string(), NWScriptType::Int)),
+        Operand::Float(..) => bytes_to_float(bytes).ok()
+          .map(|f| Expr::primary(format!("{:?}", f), NWScriptType::Float)),
+        Operand::String =>
+          Some(Expr::primary(escape_string(bytes, self.encoding), NWScriptType::String)),
+        Operand::Object(..) => bytes_to_uint(bytes).ok().map(|o| {
+          let text = match o {
+            0 => "OBJECT_SELF".to_string(),
+            OBJECT_INVALID => "OBJECT_INVALID".to_string(),
+            o => format!("{:#X}", o)
+          };
+          Expr::primary(text, NWScriptType::Object)
+        }),
+        _ => None
+      };
+      if let Some(e) = e {
+        return Ok(e);
+      }
+    }
+    Err("constant without a value".to_string())
+  }
+
+  // Pop a value of `size` bytes, joining three floats into a vector if need be
+  fn pop(&mut self, stack: &mut Vec<Entry>, size: usize) -> Result<Expr, String> {
+    let top = try!(stack.last().cloned().ok_or("stack underflow".to_string()));
+    if top.size == 4 && size == 12 {
+      let z = try!(self.pop(stack, 4));
+      let y = try!(self.pop(stack, 4));
+      let x = try!(self.pop(stack, 4));
+      return Ok(Expr::primary(format!("[{}, {}, {}]", x.text, y.text, z.text),
+                              NWScriptType::Vector));
+    }
+    if top.size != size {
+      return Err(format!("expected {} bytes on the stack, found {}", size, top.size));
+    }
+    stack.pop();
+    match top.slot {
+      Slot::Temp(e) => Ok(e),
+      Slot::Var(name, t) => Ok(Expr::primary(name, t)),
+      Slot::Return => Err("the return value slot is used as a value".to_string())
+    }
+  }
+
+  // A temporary that is read again is really a variable the compiler left in place
+  fn make_var(&mut self, stack: &mut Vec<Entry>, i: usize, out: &mut Vec<Stmt>)
+              -> Result<(), String> {
+    let t = match stack[i].slot {
+      Slot::Temp(ref e) => e.clone(),
+      Slot::Var(..) => return Ok(()),
+      Slot::Return => return Err("the return value slot is read".to_string())
+    };
+    let name = self.new_var(&t.nwtype);
+    out.push(Stmt::Decl(t.nwtype.clone(), name.clone(), Some(t.text)));
+    stack[i].slot = Slot::Var(name, t.nwtype);
+    Ok(())
+  }
+
+  // A copy of part of `stack`, as CPTOPSP or CPTOPBP would push it
+  fn read(&mut self, stack: &mut Vec<Entry>, offset: i32, size: usize, out: &mut Vec<Stmt>)
+          -> Result<Entry, String> {
+    let (i, within) = try!(find(stack, offset).ok_or(format!("offset {} is off the stack",
+                                                             offset)));
+    try!(self.make_var(stack, i, out));
+    let (name, t) = match stack[i].slot {
+      Slot::Var(ref n, ref t) => (n.clone(), t.clone()),
+      _ => unreachable!()
+    };
+    let e = if within == 0 && stack[i].size == size {
+      Expr::primary(name, t)
+    } else if stack[i].size == 12 && size == 4 {
+      Expr::primary(format!("{}.{}", name, ["x", "y", "z"][within as usize / 4]),
+                    NWScriptType::Float)
+    } else {
+      return Err(format!("can't read {} bytes at offset {}", size, offset));
+    };
+    Ok(Entry { slot: Slot::Temp(e), size: size })
+  }
+
+  // Store the value on top of the stack into part of `target`, as CPDOWNSP or CPDOWNBP would
+  fn write(&mut self,
+           stack: &mut Vec<Entry>,
+           target: Target,
+           offset: i32,
+           size: usize,
+           out: &mut Vec<Stmt>) -> Result<(), String> {
+    let value = try!(self.pop(stack, size));
+    {
+      // CPDOWNSP offsets count the value being copied, which is off the stack now
+      let (region, offset) = match target {
+        Target::Stack => (&mut *stack, offset.saturating_add(size as i32)),
+        Target::Globals => (&mut self.globals, offset)
+      };
+      let (i, within) = try!(find(region, offset).ok_or(format!("offset {} is off the stack",
+                                                                offset)));
+      // Three floats reserved one by one and then written together are a vector
+      if size == 12 && within == 0 && region[i].size == 4 && i + 3 <= region.len() {
+        let mut names = vec!();
+        for e in region[i..i + 3].iter() {
+          match e.slot {
+            Slot::Var(ref n, NWScriptType::Float) => names.push(n.clone()),
+            _ => return Err("vector written over something else".to_string())
+          }
+        }
+        for n in names.iter() {
+          forget_decl(out, n);
+        }
+        self.vars += 1;
+        let name = format!("v{}{}", self.var_style, self.vars);
+        region.splice(i..i + 3, vec!(Entry { slot: Slot::Var(name.clone(), NWScriptType::Vector),
+                                             size: 12 }));
+        out.push(Stmt::Decl(NWScriptType::Vector, name, None));
+      }
+
+      let (i, within) = find(region, offset).unwrap();
+      match region[i].slot.clone() {
+        Slot::Return => {
+          self.returned = Some(value.nwtype.clone());
+          out.push(Stmt::Return(Some(value.text.clone())));
+        },
+        Slot::Var(ref name, _) if within == 0 && region[i].size == size => {
+          let merged = match out.last_mut() {
+            Some(&mut Stmt::Decl(_, ref n, ref mut init @ None)) if n == name => {
+              *init = Some(value.text.clone());
+              true
+            },
+            _ => false
+          };
+          if !merged {
+            out.push(Stmt::Assign(name.clone(), value.text.clone()));
+          }
+        },
+        Slot::Var(ref name, NWScriptType::Vector) if size == 4 => {
+          let field = ["x", "y", "z"][within as usize / 4];
+          out.push(Stmt::Assign(format!("{}.{}", name, field), value.text.clone()));
+        },
+        Slot::Temp(ref e) if within == 0 && region[i].size == size => {
+          // the compiler reused a pushed value as a variable
+          self.vars += 1;
+          let name = format!("{}{}{}", prefix(&e.nwtype), self.var_style, self.vars);
+          out.push(Stmt::Decl(e.nwtype.clone(), name.clone(), Some(value.text.clone())));
+          region[i].slot = Slot::Var(name, e.nwtype.clone());
+        },
+        _ => return Err(format!("can't write {} bytes at offset {}", size, offset))
+      }
+    }
+    // The assigned value stays on the stack, but using it no longer repeats the side effects
+    stack.push(Entry { slot: Slot::Temp(Expr { pure: true, ..value }), size: size });
+    Ok(())
+  }
+
+  // Does the CPTOPSP at n copy the left side of a && or || for a short-circuit jump?
+  fn short_circuit(&self, n: usize) -> bool {
+    let (dup, jump) = match (self.ops.get(n), self.ops.get(n + 1)) {
+      (Some(d), Some(j)) => (d, j),
+      _ => return false
+    };
+    if dup.op.code != OpcodeE::CPTOPSP || dup.numbers() != vec!(-4, 4) {
+      return false;
+    }
+    let join = match jump.op.code {
+      OpcodeE::JZ => OpcodeE::LOGANDII,
+      OpcodeE::JNZ => OpcodeE::LOGORII,
+      _ => return false
+    };
+    jump_target(jump).and_then(|t| self.ends.get(&t))
+      .map_or(false, |m| self.ops[*m].op.code == join)
+  }
+
+  // Is everything from `t` to the end of the function just cleanup before RETN?
+  fn is_epilogue(&self, t: usize) -> bool {
+    let start = match self.index.get(&t) {
+      Some(n) => *n,
+      None => return false
+    };
+    self.ops[start..].iter()
+      .take_while(|o| o.offset <= self.end)
+      .all(|o| o.op.code == OpcodeE::MOVSP || o.op.code == OpcodeE::RETN)
+  }
+
+  fn jump(&self, t: usize, at: usize, out: &mut Vec<Stmt>) {
+    let s = match self.loops.last() {
+      Some(&(_, brk, _)) if t == brk => Stmt::Break,
+      Some(&(cont, _, body_end)) if t == cont || (t > at && t <= body_end) => Stmt::Continue,
+      _ if self.is_epilogue(t) => match out.last() {
+        Some(&Stmt::Return(_)) => return,
+        _ => Stmt::Return(None)
+      },
+      _ => Stmt::Comment(format!("goto {}", label_name(t)))
+    };
+    out.push(s);
+  }
+
+  fn call(&mut self, name: String, args: Vec<Expr>, ret: NWScriptType, stack: &mut Vec<Entry>,
+          out: &mut Vec<Stmt>) {
+    let args: Vec<String> = args.into_iter().map(|a| a.text).collect();
+    let text = format!("{}({})", name, args.join(", "));
+    let size = ret.stack_size();
+    if size == 0 {
+      out.push(Stmt::Expr(text));
+    } else {
+      stack.push(Entry { slot: Slot::Temp(Expr { text: text, nwtype: ret, prec: PRIMARY,
+                                                 pure: false }),
+                         size: size });
+    }
+  }
+
+  fn op(&mut self, n: usize, stack: &mut Vec<Entry>, out: &mut Vec<Stmt>) -> Result<(), String> {
+    let op = &self.ops[n];
+    let args = op.numbers();
+    let arg = |i: usize| args.get(i).cloned().unwrap_or(0);
+    let t = op._type.unwrap_or(0);
+
+    match op.op.code {
+      OpcodeE::RSADD => {
+        let nwtype = match t {
+          0x03 => NWScriptType::Int,
+          0x04 => NWScriptType::Float,
+          0x05 => NWScriptType::String,
+          0x06 => NWScriptType::Object,
+          t => structure_type(self.engine, t)
+        };
+        let name = self.new_var(&nwtype);
+        out.push(Stmt::Decl(nwtype.clone(), name.clone(), None));
+        stack.push(Entry { slot: Slot::Var(name, nwtype), size: 4 });
+      },
+      OpcodeE::CONST => {
+        let e = try!(self.literal(op));
+        stack.push(Entry { slot: Slot::Temp(e), size: 4 });
+      },
+      OpcodeE::CPTOPSP if self.short_circuit(n) => {
+        let top = try!(stack.last().cloned().ok_or("stack underflow".to_string()));
+        stack.push(top);
+      },
+      OpcodeE::CPTOPSP => {
+        let e = try!(self.read(stack, arg(0), arg(1) as usize, out));
+        stack.push(e);
+      },
+      OpcodeE::CPTOPBP => {
+        let mut globals = self.globals.clone();
+        let e = try!(self.read(&mut globals, arg(0), arg(1) as usize, out));
+        self.globals = globals;
+        stack.push(e);
+      },
+      OpcodeE::CPDOWNSP => try!(self.write(stack, Target::Stack, arg(0), arg(1) as usize, out)),
+      OpcodeE::CPDOWNBP =>
+        try!(self.write(stack, Target::Globals, arg(0), arg(1) as usize, out)),
+      OpcodeE::MOVSP => {
+        let mut bytes = try!(arg(0).checked_neg().ok_or(format!("MOVSP {} is out of range",
+                                                                 arg(0))));
+        while bytes > 0 {
+          let e = try!(stack.pop().ok_or("stack underflow".to_string()));
+          if e.size as i32 > bytes {
+            return Err("MOVSP splits a value".to_string());
+          }
+          if let Slot::Temp(ref e) = e.slot {
+            if !e.pure {
+              out.push(Stmt::Expr(e.text.clone()));
+            }
+          }
+          bytes -= e.size as i32;
+        }
+      },
+      OpcodeE::ACTION => {
+        let routine = try!(self.routines.get(&(arg(0) as u16))
+                           .ok_or(format!("unknown routine {}", arg(0))));
+        let mut values = vec!();
+        for a in routine.args[..(arg(1) as usize).min(routine.args.len())].iter() {
+          values.push(match *a.nwtype.base() {
+            NWScriptType::Action => Expr::primary(self.pending.take().unwrap_or("/* ? */".into()),
+                                                  NWScriptType::Action),
+            ref t => try!(self.pop(stack, t.stack_size()))
+          });
+        }
+        self.call(routine.name.clone(), values, routine.return_type.clone(), stack, out);
+      },
+      OpcodeE::JSR => {
+        let target = try!(jump_target(op).ok_or("JSR without a target".to_string()));
+        let sig = try!(self.sigs.get(&target).cloned()
+                       .ok_or(format!("JSR to {}, which isn't a subroutine", label_name(target))));
+        let mut values = vec!();
+        for p in sig.params.iter() {
+          values.push(try!(self.pop(stack, p.stack_size())));
+        }
+        let mut ret = sig.ret.clone();
+        if sig.ret_bytes > 0 {
+          // The caller reserved the result slot with RSADD before pushing the arguments
+          let mut reserved = 0;
+          let mut types = vec!();
+          while reserved < sig.ret_bytes {
+            match stack.pop() {
+              Some(Entry { slot: Slot::Var(name, t), size }) => {
+                forget_decl(out, &name);
+                types.push(t);
+                reserved += size;
+              },
+              _ => return Err("no slot reserved for the result".to_string())
+            }
+          }
+          if types.len() == 1 {
+            ret = types.pop().unwrap();
+          }
+          self.observed.entry(target).or_insert((vec!(), None)).1 = Some(ret.clone());
+        }
+        let types: Vec<NWScriptType> = values.iter().map(|v| v.nwtype.clone()).collect();
+        self.observed.entry(target).or_insert((vec!(), None)).0 = types;
+        let name = self.names.get(&target).cloned().unwrap_or(subroutine_name(target));
+        self.call(name, values, ret, stack, out);
+      },
+      OpcodeE::NEG | OpcodeE::COMPI | OpcodeE::NOTI => {
+        let e = try!(self.pop(stack, 4));
+        let sign = match op.op.code { OpcodeE::NEG => "-", OpcodeE::COMPI => "~", _ => "!" };
+        let text = format!("{}{}", sign, e.operand(UNARY));
+        stack.push(Entry { slot: Slot::Temp(Expr { text: text, nwtype: e.nwtype, prec: UNARY,
+                                                   pure: e.pure }),
+                           size: 4 });
+      },
+      OpcodeE::DECISP | OpcodeE::INCISP => {
+        let e = try!(self.read(stack, arg(0), 4, out));
+        let sign = if op.op.code == OpcodeE::INCISP { "++" } else { "--" };
+        if let Slot::Temp(e) = e.slot {
+          out.push(Stmt::Expr(format!("{}{}", e.text, sign)));
+        }
+      },
+      OpcodeE::DECIBP | OpcodeE::INCIBP => {
+        let mut globals = self.globals.clone();
+        let e = try!(self.read(&mut globals, arg(0), 4, out));
+        let sign = if op.op.code == OpcodeE::INCIBP { "++" } else { "--" };
+        if let Slot::Temp(e) = e.slot {
+          out.push(Stmt::Expr(format!("{}{}", e.text, sign)));
+        }
+      },
+      OpcodeE::DESTRUCT if arg(0) == 12 && arg(2) == 4 && arg(1) >= 0 && arg(1) < 12 &&
+        arg(1) % 4 == 0 => {
+        let e = try!(self.pop(stack, 12));
+        let text = format!("{}.{}", e.operand(PRIMARY), ["x", "y", "z"][arg(1) as usize / 4]);
+        stack.push(Entry { slot: Slot::Temp(Expr { text: text, nwtype: NWScriptType::Float,
+                                                   prec: PRIMARY, pure: e.pure }),
+                           size: 4 });
+      },
+      OpcodeE::SAVEBP => {
+        self.global_decls = out.drain(..).collect();
+        stack.push(Entry { slot: Slot::Temp(Expr::primary("BP".to_string(), NWScriptType::Int)),
+                           size: 4 });
+        self.globals = stack.clone();
+      },
+      OpcodeE::RESTOREBP => {
+        try!(stack.pop().ok_or("stack underflow".to_string()));
+      },
+      OpcodeE::NOP | OpcodeE::T => (),
+      code => match binary_operator(code) {
+        Some((symbol, prec)) => {
+          let (a_size, b_size) = match t {
+            0x24 => (arg(0) as usize, arg(0) as usize),
+            0x3A => (12, 12),
+            0x3B => (12, 4),
+            0x3C => (4, 12),
+            _ => (4, 4)
+          };
+          let b = try!(self.pop(stack, b_size));
+          let a = try!(self.pop(stack, a_size));
+          let nwtype = match (prec, t) {
+            (6..=8, _) | (1..=2, _) => NWScriptType::Int,
+            (_, 0x21) | (_, 0x25) | (_, 0x26) => NWScriptType::Float,
+            (_, 0x23) => NWScriptType::String,
+            (_, 0x3A) | (_, 0x3B) | (_, 0x3C) => NWScriptType::Vector,
+            _ => NWScriptType::Int
+          };
+          let text = format!("{} {} {}", a.operand(prec), symbol, b.operand(prec + 1));
+          stack.push(Entry { slot: Slot::Temp(Expr { text: text, nwtype: nwtype.clone(),
+                                                     prec: prec, pure: a.pure && b.pure }),
+                             size: nwtype.stack_size() });
+        },
+        None => return Err(format!("{:?} isn't supported", code))
+      }
+    }
+    Ok(())
+  }
+
+  // Decompile the instructions from start up to end, structuring the jumps inside
+  fn block(&mut self, stack: &mut Vec<Entry>, start: usize, end: usize, out: &mut Vec<Stmt>)
+           -> Result<(), String> {
+    let mut pc = start;
+    while pc < end {
+      let n = try!(self.index.get(&pc).cloned().ok_or(format!("no instruction at {}",
+                                                              label_name(pc))));
+      let op = &self.ops[n];
+      let next = op.offset + op.bytes_read;
+      let context = |e: String| format!("{}: {}", label_name(op.offset), e);
+
+      match op.op.code {
+        OpcodeE::RETN => return Ok(()),
+        OpcodeE::JZ | OpcodeE::JNZ if n > 0 && self.short_circuit(n - 1) => {
+          stack.pop();
+          pc = next;
+        },
+        OpcodeE::JZ | OpcodeE::JNZ => {
+          let t = try!(jump_target(op).ok_or(context("jump without a target".to_string())));
+          let cond = try!(self.pop(stack, 4).map_err(&context));
+          let cond = if op.op.code == OpcodeE::JZ {
+            cond.text
+          } else {
+            format!("!{}", cond.operand(UNARY))
+          };
+          let before = self.ends.get(&t).map(|m| &self.ops[*m]);
+          let back = before.filter(|b| b.op.code == OpcodeE::JMP && b.offset >= next)
+            .and_then(|b| jump_target(b).map(|to| (b.offset, to)));
+
+          match back {
+            // A loop jumps back to its condition at the end of its body
+            Some((body_end, h)) if h >= start && h <= op.offset => {
+              let mut body = vec!();
+              self.loops.push((h, t, body_end));
+              let result = self.block(&mut stack.clone(), next, body_end, &mut body);
+              self.loops.pop();
+              try!(result);
+              out.push(Stmt::While(cond, body));
+              pc = t;
+            },
+            // An if with an else jumps over the else at the end of its then
+            Some((then_end, e)) if e > t && e <= end => {
+              let mut then = vec!();
+              try!(self.block(&mut stack.clone(), next, then_end, &mut then));
+              let mut other = vec!();
+              try!(self.block(&mut stack.clone(), t, e, &mut other));
+              out.push(Stmt::If(cond, then, other));
+              pc = e;
+            },
+            _ if t > next && t <= end => {
+              let mut then = vec!();
+              try!(self.block(&mut stack.clone(), next, t, &mut then));
+              out.push(Stmt::If(cond, then, vec!()));
+              pc = t;
+            },
+            _ => return Err(context(format!("can't structure the jump to {}", label_name(t))))
+          }
+        },
+        OpcodeE::JMP => {
+          let t = try!(jump_target(op).ok_or(context("jump without a target".to_string())));
+          self.jump(t, op.offset, out);
+          pc = next;
+        },
+        OpcodeE::STORE_STATE => {
+          // The saved code runs later, on a copy of the stack, as an action argument
+          let resume = try!(state_entry(self.ops, n).ok_or(context("no JMP after STORE_STATE"
+                                                                   .to_string())));
+          let skip = try!(jump_target(&self.ops[n + 1]).ok_or(context("bad JMP".to_string())));
+          let mut body = vec!();
+          let loops = ::std::mem::replace(&mut self.loops, vec!());
+          let result = self.block(&mut stack.clone(), resume, skip, &mut body);
+          self.loops = loops;
+          try!(result);
+          let action = body.iter().filter_map(|s| match *s {
+            Stmt::Expr(ref e) => Some(e.clone()),
+            _ => None
+          }).next();
+          self.pending = Some(action.unwrap_or("/* action */".to_string()));
+          pc = skip;
+        },
+        _ => {
+          try!(self.op(n, stack, out).map_err(&context));
+          pc = next;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // Decompile one function, returning what it could even if it stopped early
+  fn function(&mut self, sub: &Subroutine, globals: bool) -> (Vec<Stmt>, Option<String>) {
+    let sig = self.sigs[&sub.start].clone();
+    self.var_style = if globals { "Global" } else { "Var" };
+    self.vars = 0;
+    self.end = self.ends.get(&sub.end).map_or(sub.end, |n| self.ops[*n].offset);
+    self.loops.clear();
+    self.pending = None;
+    self.returned = None;
+
+    let mut stack = vec!();
+    if sig.ret_bytes > 0 {
+      stack.push(Entry { slot: Slot::Return, size: sig.ret_bytes });
+    }
+    for (i, p) in sig.params.iter().enumerate().rev() {
+      stack.push(Entry { slot: Slot::Var(format!("{}Param{}", prefix(p), i + 1), p.clone()),
+                         size: p.stack_size() });
+    }
+
+    let mut out = vec!();
+    let result = self.block(&mut stack, sub.start, sub.end, &mut out);
+    fold_for(&mut out);
+    if let Some(&Stmt::Return(None)) = out.last() {
+      out.pop();
+    }
+    (out, result.err())
+  }
+
+  // Use what the call sites and returns showed about each function's types
+  fn refine(&mut self, entry: usize) {
+    let sig = self.sigs.get_mut(&entry).unwrap();
+    if let Some(&(ref params, ref ret)) = self.observed.get(&entry) {
+      let size: usize = params.iter().map(|p| p.stack_size()).sum();
+      if size == sig.arg_bytes && params.iter().all(|p| p.stack_size() > 0) {
+        sig.params = params.clone();
+      }
+      if let Some(ref ret) = *ret {
+        if ret.stack_size() == sig.ret_bytes {
+          sig.ret = ret.clone();
+        }
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+  Stack,
+  Globals
+}
+
+fn write_stmts<W: Write>(wtr: &mut W, stmts: &[Stmt], depth: usize) -> io::Result<()> {
+  let pad = "    ".repeat(depth);
+  for s in stmts.iter() {
+    match *s {
+      Stmt::Decl(ref t, ref n, None) => try!(writeln!(wtr, "{}{} {};", pad, t, n)),
+      Stmt::Decl(ref t, ref n, Some(ref e)) => try!(writeln!(wtr, "{}{} {} = {};", pad, t, n, e)),
+      Stmt::Assign(ref n, ref e) => try!(writeln!(wtr, "{}{} = {};", pad, n, e)),
+      Stmt::Expr(ref e) => try!(writeln!(wtr, "{}{};", pad, e)),
+      Stmt::Return(None) => try!(writeln!(wtr, "{}return;", pad)),
+      Stmt::Return(Some(ref e)) => try!(writeln!(wtr, "{}return {};", pad, e)),
+      Stmt::If(ref c, ref a, ref b) => {
+        try!(writeln!(wtr, "{}if ({}) {{", pad, c));
+        try!(write_stmts(wtr, a, depth + 1));
+        let mut other = b;
+        // else if chains
+        while let (1, Some(&Stmt::If(ref c, ref a, ref b))) = (other.len(), other.first()) {
+          try!(writeln!(wtr, "{}}} else if ({}) {{", pad, c));
+          try!(write_stmts(wtr, a, depth + 1));
+          other = b;
+        }
+        if other.len() > 0 {
+          try!(writeln!(wtr, "{}}} else {{", pad));
+          try!(write_stmts(wtr, other, depth + 1));
+        }
+        try!(writeln!(wtr, "{}}}", pad));
+      },
+      Stmt::While(ref c, ref b) => {
+        try!(writeln!(wtr, "{}while ({}) {{", pad, c));
+        try!(write_stmts(wtr, b, depth + 1));
+        try!(writeln!(wtr, "{}}}", pad));
+      },
+      Stmt::For(ref i, ref c, ref inc, ref b) => {
+        try!(writeln!(wtr, "{}for ({}; {}; {}) {{", pad, i, c, inc));
+        try!(write_stmts(wtr, b, depth + 1));
+        try!(writeln!(wtr, "{}}}", pad));
+      },
+      Stmt::Break => try!(writeln!(wtr, "{}break;", pad)),
+      Stmt::Continue => try!(writeln!(wtr, "{}continue;", pad)),
+      Stmt::Comment(ref c) => try!(writeln!(wtr, "{}// {}", pad, c))
+    }
+  }
+  Ok(())
+}
+
+fn prototype(name: &str, sig: &Signature) -> String {
+  let params: Vec<String> = sig.params.iter().enumerate()
+    .map(|(i, p)| format!("{} {}Param{}", p, prefix(p), i + 1))
+    .collect();
+  format!("{} {}({})", sig.ret, name, params.join(", "))
+}
+
+/// Rebuild NWScript source for the decoded instructions in `ops`, as returned by `read_ops`.
+///
+/// Stack operations are simulated to recover expressions and local variables, jumps are
+/// structured into if, while and for statements, and `ACTION` calls are typed from the
+/// routine table. Function signatures come from stack analysis and their call sites. Anything
+/// that can't be structured is left as a comment at the point the function stops.
+pub fn decompile<W: Write>(wtr: &mut W,
+                           ops: &[OpPayload],
+                           routines: &HashMap<u16, Routine>,
+                           engine: Engine,
+                           encoding: Encoding) -> io::Result<()> {
+  let subs = find_subroutines(ops);
+  let report = check_stack(ops, &build_cfg(ops), routines);
+
+  let mut sigs = HashMap::new();
+  for s in subs.iter() {
+    let (arg_bytes, ret_bytes) = match report.functions.get(&s.start) {
+      Some(f) => {
+        let args = (-f.returns.unwrap_or(0)).max(0);
+        (args as usize, (-f.lowest - args).max(0) as usize)
+      },
+      None => (0, 0)
+    };
+    sigs.insert(s.start, Signature { params: vec!(NWScriptType::Int; arg_bytes / 4),
+                                     ret: sized_type(ret_bytes), arg_bytes: arg_bytes,
+                                     ret_bytes: ret_bytes });
+  }
+
+  // Scripts with globals set them up in a function that saves BP and then calls main
+  let code: Vec<&OpPayload> = ops.iter().filter(|o| o.op.code != OpcodeE::T).collect();
+  let within = |s: &Subroutine, o: &&&OpPayload| o.offset >= s.start && o.offset < s.end;
+  let globals = subs.iter().find(|s| code.iter().filter(|o| within(s, o))
+                                     .any(|o| o.op.code == OpcodeE::SAVEBP))
+    .map(|s| s.start);
+  let caller = globals.or(subs.first().map(|s| s.start));
+  let main = caller.and_then(|c| {
+    let s = subs.iter().find(|s| s.start == c).unwrap();
+    code.iter().filter(|o| within(s, o))
+      .skip_while(|o| globals.is_some() && o.op.code != OpcodeE::SAVEBP)
+      .find(|o| o.op.code == OpcodeE::JSR)
+      .and_then(|o| jump_target(o))
+  });
+
+  let mut names = HashMap::new();
+  if let Some(main) = main {
+    let conditional = sigs.get(&main).map_or(false, |s| s.ret_bytes > 0);
+    names.insert(main, if conditional { "StartingConditional" } else { "main" }.to_string());
+  }
+
+  let mut d = Decompiler {
+    ops: ops,
+    index: ops.iter().enumerate().map(|(n, o)| (o.offset, n)).collect(),
+    ends: ops.iter().enumerate().map(|(n, o)| (o.offset + o.bytes_read, n)).collect(),
+    routines: routines,
+    engine: engine,
+    encoding: encoding,
+    names: names,
+    sigs: sigs,
+    observed: HashMap::new(),
+    globals: vec!(),
+    global_decls: vec!(),
+    var_style: "Var",
+    vars: 0,
+    end: 0,
+    loops: vec!(),
+    pending: None,
+    returned: None
+  };
+
+  // Types found at call sites feed the next pass
+  let mut bodies = vec!();
+  for pass in 0..3 {
+    bodies.clear();
+    for s in subs.iter() {
+      let (body, error) = d.function(s, Some(s.start) == globals);
+      if let Some(ret) = d.returned.take() {
+        let entry = d.observed.entry(s.start).or_insert((vec!(), None));
+        if entry.1.is_none() {
+          entry.1 = Some(ret);
+        }
+      }
+      bodies.push((s.start, body, error));
+    }
+    if pass < 2 {
+      for s in subs.iter() {
+        d.refine(s.start);
+      }
+    }
+  }
+
+  // Everything but the entry point and the globals setup is a function of the script
+  let (shown, setup): (Vec<_>, Vec<_>) = bodies.iter()
+    .partition(|b| Some(b.0) != globals && Some(b.0) != subs.first().map(|s| s.start));
+  for b in setup.iter() {
+    if let Some(ref e) = b.2 {
+      try!(writeln!(wtr, "// ox can't decompile the script setup: {}\n", e));
+    }
+  }
+  if d.global_decls.len() > 0 {
+    try!(write_stmts(wtr, &d.global_decls, 0));
+    try!(writeln!(wtr, ""));
+  }
+  let mut prototypes = false;
+  for b in shown.iter().filter(|b| Some(b.0) != main) {
+    try!(writeln!(wtr, "{};", prototype(&subroutine_name(b.0), &d.sigs[&b.0])));
+    prototypes = true;
+  }
+  if prototypes {
+    try!(writeln!(wtr, ""));
+  }
+  for (n, b) in shown.iter().enumerate() {
+    if n > 0 {
+      try!(writeln!(wtr, ""));
+    }
+    let name = d.names.get(&b.0).cloned().unwrap_or(subroutine_name(b.0));
+    try!(writeln!(wtr, "{}\n{{", prototype(&name, &d.sigs[&b.0])));
+    try!(write_stmts(wtr, &b.1, 1));
+    if let Some(ref e) = b.2 {
+      try!(writeln!(wtr, "    // ox can't decompile the rest: {}", e));
+    }
+    try!(writeln!(wtr, "}}"));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod decompile_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use disassemble::read_ops;
+  use engine::Engine;
+  use io_utils::Encoding;
+  use opcodes::get_opcodes;
+  use types::NWScriptType;
+  use {test_routine as routine, Routine};
+  use super::decompile;
+
+  // Decompile a script given as a listing
+  fn source(listing: &str) -> String {
+    let routines: HashMap<u16, Routine> = vec!(
+      routine(0, "Random", NWScriptType::Int, vec!(NWScriptType::Int)),
+      routine(1, "PrintInteger", NWScriptType::Void, vec!(NWScriptType::Int)),
+      routine(2, "PrintString", NWScriptType::Void, vec!(NWScriptType::String)),
+      routine(3, "DelayCommand", NWScriptType::Void,
+              vec!(NWScriptType::Float, NWScriptType::Action))
+    ).into_iter().map(|r| (r.code, r)).collect();
+    let opcodes = get_opcodes();
+    let mut ncs = vec!();
+    ::assemble::assemble(Cursor::new(listing), &mut ncs, &opcodes, Some(&routines),
+                         Engine::Generic, Encoding::Utf8).unwrap();
+    let (_, ops) = read_ops(&mut Cursor::new(&ncs[..]), &opcodes).unwrap();
+    let mut out = vec!();
+    decompile(&mut out, &ops, &routines, Engine::Generic, Encoding::Utf8).unwrap();
+    String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn for_loop() {
+    let listing = "\
+      T 0x00000000\n\
+      JSR main\n\
+      RETN\n\
+      main:\n\
+      RSADDI\n\
+      CONSTI 5\n\
+      ACTION Random 1\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      loop:\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 0\n\
+      GTII\n\
+      JZ done\n\
+      CPTOPSP @-4 0x4\n\
+      ACTION PrintInteger 1\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 1\n\
+      SUBII\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      JMP loop\n\
+      done:\n\
+      MOVSP @-4\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    int nVar1;
+    for (nVar1 = Random(5); nVar1 > 0; nVar1 = nVar1 - 1) {
+        PrintInteger(nVar1);
+    }
+}
+");
+  }
+
+  #[test]
+  fn branches() {
+    let listing = "\
+      T 0x00000000\n\
+      JSR main\n\
+      RETN\n\
+      main:\n\
+      RSADDI\n\
+      CONSTI 3\n\
+      ACTION Random 1\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 0\n\
+      EQUALII\n\
+      JZ not0\n\
+      CONSTI 0\n\
+      ACTION PrintInteger 1\n\
+      JMP end\n\
+      not0:\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 1\n\
+      EQUALII\n\
+      JZ other\n\
+      CONSTI 1\n\
+      ACTION PrintInteger 1\n\
+      JMP end\n\
+      other:\n\
+      CONSTI 2\n\
+      ACTION PrintInteger 1\n\
+      end:\n\
+      MOVSP @-4\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    int nVar1 = Random(3);
+    if (nVar1 == 0) {
+        PrintInteger(0);
+    } else if (nVar1 == 1) {
+        PrintInteger(1);
+    } else {
+        PrintInteger(2);
+    }
+}
+");
+  }
+
+  #[test]
+  fn break_and_continue() {
+    let listing = "\
+      T 0x00000000\n\
+      JSR main\n\
+      RETN\n\
+      main:\n\
+      RSADDI\n\
+      CONSTI 0\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      top:\n\
+      CONSTI 1\n\
+      JZ done\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 5\n\
+      GTII\n\
+      JZ small\n\
+      JMP done\n\
+      small:\n\
+      INCISP @-4\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 2\n\
+      LTII\n\
+      JZ print\n\
+      JMP top\n\
+      print:\n\
+      CPTOPSP @-4 0x4\n\
+      ACTION PrintInteger 1\n\
+      JMP top\n\
+      done:\n\
+      MOVSP @-4\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    int nVar1 = 0;
+    while (1) {
+        if (nVar1 > 5) {
+            break;
+        }
+        nVar1++;
+        if (nVar1 < 2) {
+            continue;
+        }
+        PrintInteger(nVar1);
+    }
+}
+");
+  }
+
+  #[test]
+  fn functions_and_globals() {
+    let listing = "\
+      T 0x00000000\n\
+      RSADDI\n\
+      JSR globals\n\
+      RETN\n\
+      globals:\n\
+      RSADDI\n\
+      CONSTI 10\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      SAVEBP\n\
+      RSADDI\n\
+      JSR cond\n\
+      CPDOWNSP @-16 0x4\n\
+      MOVSP @-4\n\
+      RESTOREBP\n\
+      MOVSP @-4\n\
+      RETN\n\
+      cond:\n\
+      RSADDI\n\
+      CPTOPBP @-8 0x4\n\
+      JSR twice\n\
+      CONSTI 15\n\
+      GTII\n\
+      CPDOWNSP @-8 0x4\n\
+      MOVSP @-4\n\
+      RETN\n\
+      twice:\n\
+      CPTOPSP @-4 0x4\n\
+      CONSTI 2\n\
+      MULII\n\
+      CPDOWNSP @-12 0x4\n\
+      MOVSP @-4\n\
+      MOVSP @-4\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+int nGlobal1 = 10;
+
+int sub_00000077(int nParam1);
+
+int StartingConditional()
+{
+    return sub_00000077(nGlobal1) > 15;
+}
+
+int sub_00000077(int nParam1)
+{
+    return nParam1 * 2;
+}
+");
+  }
+
+  #[test]
+  fn action_arguments() {
+    let listing = "\
+      T 0x00000000\n\
+      JSR main\n\
+      RETN\n\
+      main:\n\
+      STORE_STATE 0x0 0x0\n\
+      JMP after\n\
+      CONSTS \"later\"\n\
+      ACTION PrintString 1\n\
+      RETN\n\
+      after:\n\
+      CONSTF 1.5\n\
+      ACTION DelayCommand 2\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    DelayCommand(1.5, PrintString(\"later\"));
+}
+");
+  }
+
+  #[test]
+  fn errors() {
+    let listing = "\
+      T 0x00000000\n\
+      JSR main\n\
+      RETN\n\
+      main:\n\
+      CONSTI 1\n\
+      ACTION PrintInteger 1\n\
+      CONSTF 1.0\n\
+      CONSTF 2.0\n\
+      CONSTF 3.0\n\
+      DESTRUCT 0xC @12 0x4\n\
+      MOVSP @-4\n\
+      RETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    PrintInteger(1);
+    // ox can't decompile the rest: loc_00000032: DESTRUCT isn't supported
+}
+");
+
+    let listing = "T 0x00000000\nJSR main\nRETN\nmain:\nCONSTI 1\nACTION PrintInteger 1\n\
+                   MOVSP @-2147483648\nRETN\n";
+    assert_eq!(source(listing), "\
+void main()
+{
+    PrintInteger(1);
+    // ox can't decompile the rest: loc_00000020: MOVSP -2147483648 is out of range
+}
+");
+
+    // JSR into the middle of its own instruction
+    let listing = "T 0x00000000\nJSR @3\nRETN\n";
+    assert_eq!(source(listing), "// ox can't decompile the script setup: loc_0000000D: JSR to \
+                                 loc_00000010, which isn't a subroutine\n\n");
+  }
+}
@@ -1,19 +1,24 @@
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 //use std::io::prelude::*;
-use std::fs::File;
 use std::io;
-use std::io::{Read, Write, BufWriter, BufRead};
+use std::io::{Read, Write, BufRead};
 use std::iter::repeat;
 use std::string::String;
 
 use super::Routine;
-use opcodes::{Opcode, Operand, NWType, get_nwtypes, OpPayload, OpcodeE};
-use io_utils::{bytes_to_uint, bytes_to_int, bytes_to_float};
+use cfg::{find_subroutines, subroutine_name};
+use engine::Engine;
+use opcodes::{Opcode, Operand, NWType, OpPayload, OpcodeE};
+use io_utils::{bytes_to_uint, bytes_to_int, bytes_to_float, escape_string, Encoding};
 
 
-const HEADER_BYTES: usize = 8;
+pub const HEADER_BYTES: usize = 8;
+const LISTING_BYTES: usize = 8; // encoded bytes per row of a listing
 
+#[derive(Debug)]
 pub enum DisassemblyError {
   DataError(String),
   IOError(io::Error),
@@ -26,19 +31,77 @@ impl From<io::Error> for DisassemblyError {
   }
 }
 
+impl fmt::Display for DisassemblyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DisassemblyError::DataError(ref m) => write!(f, "{}", m),
+      DisassemblyError::IOError(ref e) => write!(f, "{}", e),
+      DisassemblyError::OpStreamError(ref m, b) => write!(f, "{} (byte {})", m, b)
+    }
+  }
+}
+
+impl Error for DisassemblyError {
+  fn description(&self) -> &str {
+    "disassembly failed"
+  }
+}
+
 // NOTE constraints between types and opcodes not really enforced, let alone strongly
 // TODO redesign to fix this and make opcodes contingent upon types or something
 
-// TODO what happens to control characters in strings? are they automatically escaped?
-
 use self::DisassemblyError::OpStreamError;
 pub type DisassemblyResult = Result<(), DisassemblyError>;
 
+/// Settings that change how `disassemble` lays out its listing.
+#[derive(Debug, Default, Clone)]
+pub struct DisassemblyOptions {
+  /// Replace relative jump offsets with generated `loc_XXXXXXXX` labels.
+  pub labels: bool,
+  /// Prefix each instruction with its file offset and encoded bytes, like objdump. Listings
+  /// in this form can't be reassembled.
+  pub listing: bool,
+  /// Group the listing by subroutine, each under a `sub_XXXXXXXX:` header with its call count.
+  pub functions: bool,
+  /// How string constants are decoded for printing.
+  pub encoding: Encoding,
+  /// The game the script is from, which names its engine structure types.
+  pub engine: Engine
+}
+
+/// The generated label name for an absolute byte offset.
+pub fn label_name(offset: usize) -> String {
+  format!("loc_{:08X}", offset)
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ")
+}
+
+/// The absolute byte offset a jump instruction transfers control to, if it is a jump.
+pub fn jump_target(payload: &OpPayload) -> Option<usize> {
+  if !payload.op.code.is_jump() {
+    return None;
+  }
+  for &(arg, ref bytes) in payload.args.iter() {
+    match *arg {
+      Operand::Offset(..) => {
+        return bytes_to_int(bytes.as_slice()).ok()
+          .map(|rel| (payload.offset as i64 + rel as i64) as usize);
+      },
+      _ => ()
+    }
+  }
+  None
+}
+
 pub fn format_output<'a, T: Write>(wtr: &mut T,
                                    payload: &'a OpPayload,
                                    routines: &HashMap<u16, Routine>,
                                    nwtypes: &[Option<NWType>],
                                    pad_str: &String,
+                                   labels: Option<&HashSet<usize>>,
+                                   encoding: Encoding
                                    ) -> Result<(), DisassemblyError>
 {
   // This could be so much cleaner with the appropriate payload struct and Show trait
@@ -117,8 +180,15 @@ pub fn format_output<'a, T: Write>(wtr: &mut T,
       },
       Operand::Offset(..) | Operand::Integer(..) => {
         let num = try!(bytes_to_int(bytes.as_slice()));
+        let label = match labels {
+          Some(labels) => jump_target(payload).and_then(|t| labels.get(&t)),
+          None => None
+        };
         match **arg { // wish we had fallthrough because nesting this sucks :S
-          Operand::Offset(..) => output!(wtr, "{}@{}", sep, num),
+          Operand::Offset(..) => match label {
+            Some(t) => output!(wtr, "{}{}", sep, label_name(*t)),
+            None => output!(wtr, "{}@{}", sep, num)
+          },
           _ => output!(wtr, "{}{}", sep, num)
         };
       },
@@ -127,8 +197,7 @@ pub fn format_output<'a, T: Write>(wtr: &mut T,
         output!(wtr, "{}{}", sep, num);
       },
       Operand::String => {
-        let s = std::str::from_utf8(bytes.as_slice()).unwrap();
-        output!(wtr, "{}\"{}\"", sep, s);
+        output!(wtr, "{}{}", sep, escape_string(bytes.as_slice(), encoding));
       }
     }
     skip_type = false;
@@ -153,7 +222,8 @@ pub fn disassemble_op<'a, T: Read>(asm: &mut T,
       op_err!(byte_count + bytes_read, "Unknown opcode {:#04X}", byte_buf[0])
     }
   };
-  let mut payload = OpPayload{ bytes_read: bytes_read, op: op, _type: None, args: vec!() };
+  let mut payload = OpPayload{ offset: byte_count, bytes_read: bytes_read, op: op, _type: None,
+                               args: vec!() };
 
   // Get the type byte - type of bytes that may be popped off the stack
   // determines legal args, but isn't necessarily the type of them
@@ -237,35 +307,8 @@ pub fn disassemble_op<'a, T: Read>(asm: &mut T,
   Ok(payload)
 }
 
-pub fn disassemble<S: BufRead>(asm: &mut S,
-                               opcodes: &[Option<Opcode>],
-                               routines: &HashMap<u16, Routine>,
-                               output_name: Option<&String>
-                               ) -> Result<(), DisassemblyError> {
-
-  let nwtypes = get_nwtypes();
-  let mut wtr = BufWriter::new(match output_name {
-    Some(path) => Box::new(try!(File::create(path))) as Box<Write>,
-    None => Box::new(std::io::stdout())  as Box<Write>
-  });
-
-  // The first HEADER_BYTES bytes should be a header string
-  let mut header = [0 as u8; HEADER_BYTES];
-  let mut bytes_read = read_exact!(asm, &mut header, header.len(), 0);
-  output!(wtr, ";;{}\n", std::str::from_utf8(&header).unwrap());
-
-  // Maybe payload & opcode should be the same type???
-  let t = try!(disassemble_op(asm, opcodes, bytes_read));
-  let expected_len = try!(bytes_to_uint(t.args[0].1.as_slice())) as usize;
-  bytes_read += t.bytes_read;
-
-  match t.op.code {
-    OpcodeE::T => (),
-    _ => {
-      op_err!(t.bytes_read, "Unexpected opcode {:#04X}, expected T (0x42)", t.op.code);
-    }
-  }
-
+// A run of spaces as wide as the longest opcode name, for aligning operands
+fn pad_string(opcodes: &[Option<Opcode>], nwtypes: &[Option<NWType>]) -> String {
   // Find the longest combination of opcode + type abbr (using only types legal for each op)
   let longest_code = opcodes.iter()
     .filter_map(|c| match *c {
@@ -290,24 +333,57 @@ pub fn disassemble<S: BufRead>(asm: &mut S,
     .max().unwrap();
 
   // Generate a padding string for formatting indentation after opcodes
-  let pad_str = String::from_utf8(repeat(0x20)
-                                  .take(longest_code)
-                                  .collect::<Vec<u8>>()
-                                  ).unwrap();
-  try!(format_output(&mut wtr, &t, routines, &nwtypes, &pad_str));
+  String::from_utf8(repeat(0x20)
+                    .take(longest_code)
+                    .collect::<Vec<u8>>()
+                    ).unwrap()
+}
 
+/// Format a single instruction as it would appear in a listing, without the trailing newline.
+pub fn format_instruction(payload: &OpPayload,
+                          opcodes: &[Option<Opcode>],
+                          routines: &HashMap<u16, Routine>,
+                          engine: Engine,
+                          encoding: Encoding) -> Result<String, DisassemblyError> {
+  let nwtypes = engine.nwtypes();
+  let mut buf = vec!();
+  try!(format_output(&mut buf, payload, routines, &nwtypes, &pad_string(opcodes, &nwtypes), None,
+                     encoding));
+  buf.pop();
+  Ok(String::from_utf8_lossy(&buf).into_owned())
+}
 
+/// Read the header and every instruction from an NCS stream, checking the length against T.
+///
+/// The first instruction returned is always T.
+pub fn read_ops<'a, S: Read>(asm: &mut S,
+                             opcodes: &'a [Option<Opcode>]
+                             ) -> Result<([u8; HEADER_BYTES], Vec<OpPayload<'a>>),
+                                         DisassemblyError> {
+  // The first HEADER_BYTES bytes should be a header string
+  let mut header = [0 as u8; HEADER_BYTES];
+  let mut bytes_read = read_exact!(asm, &mut header, header.len(), 0);
 
-  // TODO allow user to specify decimal or hex output for integers
-  // TODO allow user to specify tabs or spaces
+  // Maybe payload & opcode should be the same type???
+  let t = try!(disassemble_op(asm, opcodes, bytes_read));
+  bytes_read += t.bytes_read;
+
+  match t.op.code {
+    OpcodeE::T => (),
+    _ => {
+      op_err!(t.bytes_read, "Unexpected opcode {:#04X}, expected T (0x42)", t.op.code);
+    }
+  }
+  let expected_len = try!(bytes_to_uint(t.args[0].1.as_slice())) as usize;
+  let mut ops = vec!(t);
 
   /* Start parsing the command stream */
   // TODO handle special cases for STORE_STATE and co. to ensure they are followed by a JMP
   // and a block of code (block = RTN bounded)
   loop {
     let c = try!(disassemble_op(asm, opcodes, bytes_read));
-    bytes_read += c.bytes_read;// TODO rename start
-    try!(format_output(&mut wtr, &c, routines, &nwtypes, &pad_str));
+    bytes_read += c.bytes_read;
+    ops.push(c);
     if bytes_read == expected_len {
       break;
     } else if bytes_read > expected_len {
@@ -316,5 +392,116 @@ pub fn disassemble<S: BufRead>(asm: &mut S,
     }
   }
 
+  Ok((header, ops))
+}
+
+/// Disassemble an NCS stream from `asm`, writing the listing to `wtr`.
+pub fn disassemble<S: BufRead, W: Write>(asm: &mut S,
+                                         wtr: &mut W,
+                                         opcodes: &[Option<Opcode>],
+                                         routines: &HashMap<u16, Routine>,
+                                         options: &DisassemblyOptions
+                                         ) -> Result<(), DisassemblyError> {
+
+  let nwtypes = options.engine.nwtypes();
+  let (header, ops) = try!(read_ops(asm, opcodes));
+  output!(wtr, ";;{}\n", String::from_utf8_lossy(&header));
+
+  let pad_str = pad_string(opcodes, &nwtypes);
+
+  // Only label targets that land on an instruction; anything else keeps its raw offset
+  let labels = if options.labels {
+    let starts: HashSet<usize> = ops.iter().map(|c| c.offset).collect();
+    Some(ops.iter()
+         .filter_map(|c| jump_target(c))
+         .filter(|t| starts.contains(t))
+         .collect::<HashSet<usize>>())
+  } else {
+    None
+  };
+
+  let subroutines = if options.functions { find_subroutines(&ops) } else { vec!() };
+
+  // TODO allow user to specify decimal or hex output for integers
+  // TODO allow user to specify tabs or spaces
+  for c in ops.iter() {
+    if let Some(sub) = subroutines.iter().find(|s| s.start == c.offset) {
+      let calls = match sub.calls {
+        1 => "called once".to_string(),
+        n => format!("called {} times", n)
+      };
+      let name = sub.name.map_or(String::new(), |n| format!("{}, ", n));
+      output!(wtr, "\n;;{}{:08X}-{:08X}, {}\n", name, sub.start, sub.end, calls);
+      output!(wtr, "{}:\n", subroutine_name(c.offset));
+    }
+    if labels.as_ref().map_or(false, |l| l.contains(&c.offset)) {
+      output!(wtr, "{}:\n", label_name(c.offset));
+    }
+    if !options.listing {
+      try!(format_output(wtr, c, routines, &nwtypes, &pad_str, labels.as_ref(),
+                         options.encoding));
+      continue;
+    }
+
+    // Long instructions (strings, mostly) wrap their bytes over extra rows
+    let bytes = c.encoded();
+    let mut rows = bytes.chunks(LISTING_BYTES);
+    let width = LISTING_BYTES * 3 - 1;
+    output!(wtr, "{:08X}:  {:<2$}  ", c.offset, hex_bytes(rows.next().unwrap()), width);
+    try!(format_output(wtr, c, routines, &nwtypes, &pad_str, labels.as_ref(),
+                       options.encoding));
+    for (n, row) in rows.enumerate() {
+      output!(wtr, "{:08X}:  {}\n", c.offset + (n + 1) * LISTING_BYTES, hex_bytes(row));
+    }
+  }
+
   Ok(())
 }
+
+#[cfg(test)]
+mod disassemble_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use opcodes::get_opcodes;
+  use super::{disassemble, DisassemblyOptions};
+
+  // T, JSR +8, RETN, RETN
+  const SCRIPT: &'static [u8] = b"NCS V1.0\x42\x00\x00\x00\x17\x1e\x00\x00\x00\x00\x08\
+                                  \x20\x00\x20\x00";
+
+  #[test]
+  fn labels() {
+    let opcodes = get_opcodes();
+    let options = DisassemblyOptions { labels: true, ..Default::default() };
+    let mut out = vec!();
+    assert!(disassemble(&mut Cursor::new(SCRIPT), &mut out, &opcodes, &HashMap::new(),
+                        &options).is_ok());
+    let listing = String::from_utf8(out).unwrap();
+    assert!(listing.contains("JSR           loc_00000015\n"));
+    assert!(listing.contains("RETN\nloc_00000015:\nRETN\n"));
+  }
+
+  #[test]
+  fn listing() {
+    let opcodes = get_opcodes();
+    let options = DisassemblyOptions { listing: true, ..Default::default() };
+    let mut out = vec!();
+    assert!(disassemble(&mut Cursor::new(SCRIPT), &mut out, &opcodes, &HashMap::new(),
+                        &options).is_ok());
+    let listing = String::from_utf8(out).unwrap();
+    assert!(listing.contains("0000000D:  1E 00 00 00 00 08        JSR           @8\n"));
+  }
+
+  #[test]
+  fn functions() {
+    let opcodes = get_opcodes();
+    let options = DisassemblyOptions { functions: true, ..Default::default() };
+    let mut out = vec!();
+    assert!(disassemble(&mut Cursor::new(SCRIPT), &mut out, &opcodes, &HashMap::new(),
+                        &options).is_ok());
+    let listing = String::from_utf8(out).unwrap();
+    assert!(listing.contains("\n;;entry point, 0000000D-00000015, called 0 times\n\
+                              sub_0000000D:\n"));
+    assert!(listing.contains("\n;;main, 00000015-00000017, called once\nsub_00000015:\nRETN\n"));
+  }
+}
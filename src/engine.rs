@@ -0,0 +1,329 @@
+use std::fmt;
+use std::io::Cursor;
+
+use disassemble::{read_ops, DisassemblyError, HEADER_BYTES};
+use io_utils::bytes_to_uint;
+use opcodes::{get_nwtypes, get_opcodes, NWType, NWTypeE, Opcode, OpcodeE, Operand};
+
+
+/// A game whose scripts ox can read, each with its own engine structures and opcode quirks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+  Generic, // everything any engine might use, which can mislabel scripts from a specific game
+  NWN1,
+  NWN2,
+  KotOR,
+  DAO,
+  DA2
+}
+
+pub const ENGINES: [Engine; 6] =
+  [Engine::Generic, Engine::NWN1, Engine::NWN2, Engine::KotOR, Engine::DAO, Engine::DA2];
+
+// Engine structures take type codes 0x10 + n on their own and 0x30 + n in comparisons
+const SINGLES: [NWTypeE; 10] = [
+  NWTypeE::Effect, NWTypeE::Event, NWTypeE::Location, NWTypeE::Talent, NWTypeE::Unknownx14,
+  NWTypeE::Unknownx15, NWTypeE::Unknownx16, NWTypeE::Unknownx17, NWTypeE::Unknownx18,
+  NWTypeE::Unknownx19
+];
+const PAIRS: [NWTypeE; 10] = [
+  NWTypeE::EffectEffect, NWTypeE::EventEvent, NWTypeE::LocationLocation, NWTypeE::TalentTalent,
+  NWTypeE::Unknownx34, NWTypeE::Unknownx35, NWTypeE::Unknownx36, NWTypeE::Unknownx37,
+  NWTypeE::Unknownx38, NWTypeE::Unknownx39
+];
+
+const NWN_STRUCTURES: [(&'static str, &'static str); 5] = [
+  ("Effect", "Effect, Effect"), ("Event", "Event, Event"), ("Location", "Location, Location"),
+  ("Talent", "Talent, Talent"), ("ItemProperty", "ItemProperty, ItemProperty")
+];
+// TODO check these against more DA scripts, the order after location is a guess; --engine's
+// help says so
+const DA_STRUCTURES: [(&'static str, &'static str); 6] = [
+  ("Effect", "Effect, Effect"), ("Event", "Event, Event"), ("Location", "Location, Location"),
+  ("Command", "Command, Command"), ("ItemProperty", "ItemProperty, ItemProperty"),
+  ("Player", "Player, Player")
+];
+
+fn is_single(t: u8) -> bool {
+  t >= SINGLES[0] as u8 && t <= SINGLES[9] as u8
+}
+
+fn is_pair(t: u8) -> bool {
+  t >= PAIRS[0] as u8 && t <= PAIRS[9] as u8
+}
+
+impl Engine {
+  /// Look up an engine by its command line name, e.g. `nwn2` or `da2`.
+  pub fn from_name(name: &str) -> Option<Engine> {
+    ENGINES.iter().find(|e| e.name() == name.to_lowercase()).map(|e| *e)
+  }
+
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Engine::Generic => "generic",
+      Engine::NWN1 => "nwn",
+      Engine::NWN2 => "nwn2",
+      Engine::KotOR => "kotor",
+      Engine::DAO => "dao",
+      Engine::DA2 => "da2"
+    }
+  }
+
+  /// Whether the engine's definitions number routines by position, like NWN's `nwscript.nss`.
+  pub fn nwn_definitions(&self) -> bool {
+    match *self {
+      Engine::NWN1 | Engine::NWN2 | Engine::KotOR => true,
+      _ => false
+    }
+  }
+
+  /// The engine structure names, in type code order, with the descriptions of their pairs.
+  /// Generic has none of its own.
+  pub fn structures(&self) -> &'static [(&'static str, &'static str)] {
+    match *self {
+      Engine::Generic => &[],
+      Engine::NWN1 | Engine::NWN2 => &NWN_STRUCTURES,
+      Engine::KotOR => &NWN_STRUCTURES[..4],
+      Engine::DAO | Engine::DA2 => &DA_STRUCTURES
+    }
+  }
+
+  /// The opcode table for scripts from this engine. Opcodes that take engine structures get
+  /// exactly the engine's structure types.
+  pub fn opcodes(&self) -> Box<[Option<Opcode>]> {
+    let mut opcodes = get_opcodes();
+    if *self == Engine::Generic {
+      return opcodes;
+    }
+    let n = self.structures().len() as u8;
+
+    for slot in opcodes.iter_mut() {
+      if slot.as_ref().map_or(false, |o| o.code == OpcodeE::CP_x37_DA2_QQ) &&
+        *self != Engine::DA2 {
+        *slot = None;
+      }
+      let op = match *slot {
+        Some(ref mut op) => op,
+        None => continue
+      };
+      let types = match op.types {
+        Some(ref mut types) => types,
+        None => continue
+      };
+
+      let singles = types.iter().any(|t| is_single(*t));
+      let pairs = types.iter().any(|t| is_pair(*t));
+      types.retain(|t| !is_single(*t) && !is_pair(*t));
+      if singles {
+        types.extend((0..n).map(|i| SINGLES[i as usize] as u8));
+      }
+      if pairs {
+        types.extend((0..n).map(|i| PAIRS[i as usize] as u8));
+      }
+      if let Some(ref mut args) = op.args {
+        args.retain(|t, _| types.contains(t));
+        for t in types.iter() {
+          args.entry(*t).or_insert(vec!());
+        }
+      }
+    }
+    opcodes
+  }
+
+  /// The stack types for scripts from this engine, with its engine structures named.
+  pub fn nwtypes(&self) -> Box<[Option<NWType>]> {
+    let mut nwtypes = get_nwtypes();
+    if *self == Engine::Generic {
+      return nwtypes;
+    }
+    for i in 0..SINGLES.len() {
+      let (single, pair) = match self.structures().get(i) {
+        Some(&(name, pair)) => (Some(NWType { code: SINGLES[i], abbr: None, desc: name }),
+                                Some(NWType { code: PAIRS[i], abbr: None, desc: pair })),
+        None => (None, None)
+      };
+      nwtypes[SINGLES[i] as usize] = single;
+      nwtypes[PAIRS[i] as usize] = pair;
+    }
+    nwtypes
+  }
+}
+
+impl Default for Engine {
+  fn default() -> Engine {
+    Engine::Generic
+  }
+}
+
+impl fmt::Display for Engine {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+/// How well one engine explains a script, from `detect`.
+#[derive(Debug)]
+pub struct Candidate {
+  pub engine: Engine,
+  pub confidence: f32, // 0 to 1, summing to 1 over all candidates
+  pub notes: Vec<String>
+}
+
+/// The engines a script could have come from, most likely first.
+#[derive(Debug)]
+pub struct Detection {
+  pub candidates: Vec<Candidate>
+}
+
+impl Detection {
+  /// The most likely engine. Generic if no single game fits, or if several fit equally well.
+  pub fn best(&self) -> Engine {
+    match self.candidates.get(1) {
+      Some(c) if c.confidence == self.candidates[0].confidence => Engine::Generic,
+      _ => self.candidates[0].engine
+    }
+  }
+}
+
+impl fmt::Display for Detection {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    try!(write!(f, "{} ({:.0}%)", self.best(), self.candidates[0].confidence * 100.0));
+    for c in self.candidates.iter() {
+      try!(write!(f, "\n  {:<8} {:>3.0}%", c.engine.name(), c.confidence * 100.0));
+      if c.notes.len() > 0 {
+        try!(write!(f, "  {}", c.notes.join("; ")));
+      }
+    }
+    Ok(())
+  }
+}
+
+// Routines in each game's last nwscript.nss; ACTION can't call anything past these. DA has
+// no limit here because its routine codes are assigned rather than positional.
+fn routine_count(engine: Engine) -> Option<u32> {
+  match engine {
+    Engine::NWN1 => Some(848),
+    Engine::NWN2 => Some(1058),
+    Engine::KotOR => Some(880),
+    _ => None
+  }
+}
+
+/// Guess which engine compiled `data`, by trying each engine's opcode table and weighing the
+/// opcodes and `ACTION` routine codes it uses.
+///
+/// Fails only if the script can't be decoded with any table.
+pub fn detect(data: &[u8]) -> Result<Detection, DisassemblyError> {
+  // Generic decodes anything any engine can, so if it fails nothing will
+  let generic = get_opcodes();
+  try!(read_ops(&mut Cursor::new(data), &generic));
+  let header_ok = data.starts_with(b"NCS V1.0");
+
+  let mut candidates = vec!();
+  for engine in ENGINES.iter().filter(|e| **e != Engine::Generic) {
+    let opcodes = engine.opcodes();
+    let mut notes = vec!();
+    if !header_ok {
+      notes.push(format!("unexpected header \"{}\"",
+                         String::from_utf8_lossy(&data[..HEADER_BYTES])));
+    }
+
+    let ops = match read_ops(&mut Cursor::new(data), &opcodes) {
+      Ok((_, ops)) => ops,
+      Err(e) => {
+        notes.push(format!("can't decode: {}", e));
+        candidates.push(Candidate { engine: *engine, confidence: 0.0, notes: notes });
+        continue;
+      }
+    };
+
+    let mut score = 1.0;
+    if ops.iter().any(|o| o.op.code == OpcodeE::CP_x37_DA2_QQ) {
+      score *= 4.0;
+      notes.push("uses opcode 0x37".to_string());
+    }
+    let highest = ops.iter()
+      .flat_map(|o| o.args.iter())
+      .filter_map(|&(arg, ref bytes)| match *arg {
+        Operand::Routine(_) => bytes_to_uint(bytes).ok(),
+        _ => None
+      })
+      .max();
+    match (highest, routine_count(*engine)) {
+      (Some(h), Some(n)) if h >= n => {
+        score *= 0.1;
+        notes.push(format!("calls routine {} but has only {}", h, n));
+      },
+      _ => ()
+    }
+    candidates.push(Candidate { engine: *engine, confidence: score, notes: notes });
+  }
+
+  let total: f32 = candidates.iter().map(|c| c.confidence).sum();
+  if total == 0.0 {
+    candidates.insert(0, Candidate { engine: Engine::Generic, confidence: 1.0,
+                                     notes: vec!("no single engine's table fits".to_string()) });
+  } else {
+    for c in candidates.iter_mut() {
+      c.confidence /= total;
+    }
+  }
+  // stable, so ties keep ENGINES order
+  candidates.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap());
+  Ok(Detection { candidates: candidates })
+}
+
+#[cfg(test)]
+mod engine_tests {
+  use opcodes::OpcodeE;
+  use super::{detect, Engine};
+
+  #[test]
+  fn profiles() {
+    let generic = Engine::Generic.opcodes();
+    let types = generic[OpcodeE::RSADD as usize].as_ref().unwrap().types.clone().unwrap();
+    assert!(types.contains(&0x10) && types.contains(&0x19));
+
+    let nwn = Engine::NWN1.opcodes();
+    let equal = nwn[OpcodeE::EQUAL as usize].as_ref().unwrap();
+    assert!(equal.types.as_ref().unwrap().contains(&0x34));
+    assert!(!equal.types.as_ref().unwrap().contains(&0x35));
+    assert!(equal.args.as_ref().unwrap().contains_key(&0x34));
+    let rsadd = nwn[OpcodeE::RSADD as usize].as_ref().unwrap();
+    assert_eq!(rsadd.types, Some(vec!(0x03, 0x04, 0x05, 0x06, 0x10, 0x11, 0x12, 0x13, 0x14)));
+    assert!(nwn[OpcodeE::CP_x37_DA2_QQ as usize].is_none());
+    assert!(Engine::DA2.opcodes()[OpcodeE::CP_x37_DA2_QQ as usize].is_some());
+
+    let kotor = Engine::KotOR.opcodes();
+    let types = kotor[OpcodeE::RSADD as usize].as_ref().unwrap().types.clone().unwrap();
+    assert!(types.contains(&0x13) && !types.contains(&0x14));
+    assert_eq!(Engine::KotOR.nwtypes()[0x13].as_ref().unwrap().desc, "Talent");
+    assert!(Engine::KotOR.nwtypes()[0x14].is_none());
+    assert_eq!(Engine::NWN2.nwtypes()[0x34].as_ref().unwrap().desc, "ItemProperty, ItemProperty");
+    assert_eq!(Engine::DAO.nwtypes()[0x13].as_ref().unwrap().desc, "Command");
+    assert_eq!(Engine::from_name("KotOR"), Some(Engine::KotOR));
+  }
+
+  #[test]
+  fn detection() {
+    // T, CP_x37_DA2_QQ, RETN
+    let da2 = b"NCS V1.0\x42\x00\x00\x00\x17\x37\x01\x00\x00\x00\x00\x00\x04\x20\x00";
+    let d = detect(da2).unwrap();
+    assert_eq!(d.best(), Engine::DA2);
+    assert_eq!(d.candidates[0].confidence, 1.0);
+
+    // T, ACTION 900 with no arguments, RETN
+    let nwn2 = b"NCS V1.0\x42\x00\x00\x00\x14\x05\x00\x03\x84\x00\x20\x00";
+    let d = detect(nwn2).unwrap();
+    let nwn1 = d.candidates.iter().find(|c| c.engine == Engine::NWN1).unwrap();
+    assert!(nwn1.confidence < 0.1);
+    // NWN2 and DA:O fit equally well
+    assert_eq!(d.best(), Engine::Generic);
+
+    // T, RETN fits every engine
+    let d = detect(b"NCS V1.0\x42\x00\x00\x00\x0F\x20\x00").unwrap();
+    assert!(d.candidates.iter().all(|c| c.confidence == 0.2));
+    assert_eq!(d.best(), Engine::Generic);
+    assert!(d.to_string().starts_with("generic (20%)"));
+  }
+}
@@ -0,0 +1,90 @@
+use std;
+use std::fmt;
+use std::io;
+
+use super::DefinitionError;
+use assemble::AssemblyError;
+use disassemble::DisassemblyError;
+use opcodes::OpcodeTableError;
+use stack::StackProblem;
+use trace::TraceError;
+use verify::VerifyError;
+
+
+/// Any failure ox can report, along with the file it happened in.
+#[derive(Debug)]
+pub enum Error {
+  Usage(String),
+  IO(String, io::Error),
+  Definitions(String, DefinitionError),
+  Opcodes(String, OpcodeTableError),
+  Disassembly(String, DisassemblyError),
+  Assembly(String, AssemblyError),
+  Verify(String, VerifyError),
+  Stack(String, Vec<StackProblem>),
+  Trace(String, TraceError)
+}
+
+impl Error {
+  /// The process exit code for this kind of failure. Panics exit with 101, so anything else
+  /// means the input was at fault rather than ox.
+  pub fn exit_code(&self) -> i32 {
+    match *self {
+      Error::Usage(..) => 1,
+      Error::IO(..) => 2,
+      Error::Disassembly(_, DisassemblyError::IOError(..)) => 2,
+      Error::Assembly(_, AssemblyError::IOError(..)) => 2,
+      Error::Verify(_, VerifyError::Disassembly(DisassemblyError::IOError(..))) => 2,
+      Error::Trace(_, TraceError::IOError(..)) => 2,
+      Error::Definitions(..) | Error::Opcodes(..) => 3,
+      Error::Disassembly(..) => 4,
+      Error::Assembly(..) => 5,
+      Error::Verify(_, VerifyError::Disassembly(..)) => 4,
+      Error::Verify(..) => 6,
+      Error::Stack(..) => 7,
+      Error::Trace(_, TraceError::Disassembly(..)) => 4,
+      Error::Trace(..) => 8
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Usage(ref m) => write!(f, "{}", m),
+      Error::IO(ref path, ref e) => write!(f, "{}: {}", path, e),
+      Error::Definitions(_, DefinitionError::Preprocess(ref e)) => write!(f, "{}", e),
+      Error::Definitions(ref path, ref e) => write!(f, "{}: {}", path, e),
+      Error::Opcodes(ref path, ref e) => write!(f, "{}: {}", path, e),
+      Error::Disassembly(ref path, ref e) => write!(f, "{}: disassembly failed: {}", path, e),
+      Error::Assembly(ref path, AssemblyError::SourceError(ref loc, ref e)) =>
+        write!(f, "assembly failed: {}\n --> {}:{}:{}\n{}", e, path, loc.line, loc.column, loc),
+      Error::Assembly(ref path, ref e) => write!(f, "{}: assembly failed: {}", path, e),
+      Error::Verify(ref path, ref e) => write!(f, "{}: {}", path, e),
+      Error::Stack(ref path, ref problems) => {
+        try!(write!(f, "{}: stack check failed", path));
+        for p in problems.iter() {
+          try!(write!(f, "\n  {}", p));
+        }
+        Ok(())
+      },
+      Error::Trace(ref path, ref e) => write!(f, "{}: {}", path, e)
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn description(&self) -> &str {
+    match *self {
+      Error::Usage(..) => "invalid arguments",
+      Error::IO(..) => "I/O error",
+      Error::Definitions(..) => "invalid definitions",
+      Error::Opcodes(..) => "invalid opcode table",
+      Error::Disassembly(..) => "disassembly failed",
+      Error::Assembly(..) => "assembly failed",
+      Error::Verify(..) => "verification failed",
+      Error::Stack(..) => "stack check failed",
+      Error::Trace(..) => "trace failed"
+    }
+  }
+}
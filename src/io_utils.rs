@@ -1,3 +1,4 @@
+use std;
 use std::fs::File;
 use std::io;
 use std::io::Cursor;
@@ -62,3 +63,137 @@ pub fn bytes_to_float(data: &[u8]) -> Result<f32, DisassemblyError> {
     }
   }
 }
+
+/// The text encoding of string constants in a script.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+  Utf8,
+  Windows1252,
+  Latin1
+}
+
+impl Default for Encoding {
+  fn default() -> Self {
+    Encoding::Utf8
+  }
+}
+
+impl Encoding {
+  pub fn from_name(name: &str) -> Option<Encoding> {
+    match name.to_lowercase().as_ref() {
+      "utf-8" | "utf8" => Some(Encoding::Utf8),
+      "windows-1252" | "cp1252" => Some(Encoding::Windows1252),
+      "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+      _ => None
+    }
+  }
+}
+
+// Windows-1252 differs from Latin-1 only in 0x80 - 0x9F; None marks the unassigned bytes
+const CP1252_HIGH: [Option<char>; 32] = [
+  Some('\u{20AC}'), None, Some('\u{201A}'), Some('\u{0192}'),
+  Some('\u{201E}'), Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'),
+  Some('\u{02C6}'), Some('\u{2030}'), Some('\u{0160}'), Some('\u{2039}'),
+  Some('\u{0152}'), None, Some('\u{017D}'), None,
+  None, Some('\u{2018}'), Some('\u{2019}'), Some('\u{201C}'),
+  Some('\u{201D}'), Some('\u{2022}'), Some('\u{2013}'), Some('\u{2014}'),
+  Some('\u{02DC}'), Some('\u{2122}'), Some('\u{0161}'), Some('\u{203A}'),
+  Some('\u{0153}'), None, Some('\u{017E}'), Some('\u{0178}')
+];
+
+// Decode the character at the start of data, returning it and the number of bytes it used.
+// Bytes that aren't valid in the encoding come back as None, one at a time.
+fn decode_char(data: &[u8], encoding: Encoding) -> (Option<char>, usize) {
+  let b = data[0];
+  match encoding {
+    Encoding::Utf8 => {
+      let len = match b {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return (None, 1)
+      };
+      if len > data.len() {
+        return (None, 1);
+      }
+      match std::str::from_utf8(&data[..len]) {
+        Ok(s) => (s.chars().next(), len),
+        Err(_) => (None, 1)
+      }
+    },
+    Encoding::Windows1252 if b >= 0x80 && b < 0xA0 => (CP1252_HIGH[(b - 0x80) as usize], 1),
+    Encoding::Windows1252 | Encoding::Latin1 => (Some(b as char), 1)
+  }
+}
+
+/// Encode a single character, or None if the encoding can't represent it.
+pub fn encode_char(c: char, encoding: Encoding) -> Option<Vec<u8>> {
+  match encoding {
+    Encoding::Utf8 => {
+      let mut utf8 = [0 as u8; 4];
+      Some(c.encode_utf8(&mut utf8).as_bytes().to_vec())
+    },
+    Encoding::Windows1252 => match CP1252_HIGH.iter().position(|h| *h == Some(c)) {
+      Some(n) => Some(vec!(0x80 + n as u8)),
+      None if (c as u32) < 0x80 || ((c as u32) >= 0xA0 && (c as u32) < 0x100) => {
+        Some(vec!(c as u8))
+      },
+      None => None
+    },
+    Encoding::Latin1 if (c as u32) < 0x100 => Some(vec!(c as u8)),
+    Encoding::Latin1 => None
+  }
+}
+
+/// Decode a string constant, replacing any bytes that aren't valid in the encoding.
+pub fn decode_string(data: &[u8], encoding: Encoding) -> String {
+  let mut out = String::with_capacity(data.len());
+  let mut rest = data;
+  while rest.len() > 0 {
+    let (c, n) = decode_char(rest, encoding);
+    out.push(c.unwrap_or('\u{FFFD}'));
+    rest = &rest[n..];
+  }
+  out
+}
+
+/// Quote a string operand for a listing, escaping quotes, backslashes and control characters.
+/// Bytes that can't be decoded, or decode to control characters, are written as `\xNN`.
+pub fn escape_string(data: &[u8], encoding: Encoding) -> String {
+  let mut out = String::with_capacity(data.len() + 2);
+  out.push('"');
+  let mut rest = data;
+  while rest.len() > 0 {
+    let (c, n) = decode_char(rest, encoding);
+    match c {
+      Some('"') => out.push_str("\\\""),
+      Some('\\') => out.push_str("\\\\"),
+      Some('\n') => out.push_str("\\n"),
+      Some('\r') => out.push_str("\\r"),
+      Some('\t') => out.push_str("\\t"),
+      Some(c) if !c.is_control() => out.push(c),
+      _ => for b in rest[..n].iter() {
+        out.push_str(&format!("\\x{:02X}", b));
+      }
+    }
+    rest = &rest[n..];
+  }
+  out.push('"');
+  out
+}
+
+#[cfg(test)]
+mod io_utils_tests {
+  use super::{escape_string, Encoding};
+
+  #[test]
+  fn escape_invalid_utf8() {
+    assert_eq!(escape_string(b"caf\xe9\n", Encoding::Utf8), "\"caf\\xE9\\n\"");
+  }
+
+  #[test]
+  fn escape_windows_1252() {
+    assert_eq!(escape_string(b"\x80\x81\xe9", Encoding::Windows1252), "\"\u{20AC}\\x81\u{E9}\"");
+  }
+}
@@ -0,0 +1,240 @@
+//! ox is an NWScript bytecode disassembler and assembler.
+//!
+//! The usual flow is to run an engine definitions file through `preprocess`, parse it with
+//! `parse_definitions` (or `parse_nwn_definitions` for NWN's `nwscript.nss`), build the
+//! lookup tables with `build_tables` and evaluate constant references with
+//! `resolve_constants`, then hand the routine table and the opcode table from `get_opcodes`
+//! (or `Engine::opcodes` for one game, or `parse_opcodes` for a custom table) to
+//! `disassemble` or `assemble`. `verify` checks that a script survives a round trip through
+//! both, and `cfg` builds control-flow graphs from the instructions `read_ops` decodes, which
+//! `stack` checks for consistent stack use, `vm` runs, calling engine routines through an
+//! `actions::Registry`, and `decompile` turns back into NWScript source.
+
+extern crate byteorder;
+
+#[macro_use]
+mod macros;
+mod error;
+pub mod opcodes;
+pub mod engine;
+mod io_utils;
+pub mod preprocess;
+mod resolve;
+pub mod types;
+pub mod disassemble;
+pub mod assemble;
+pub mod verify;
+pub mod cfg;
+pub mod stack;
+pub mod vm;
+pub mod actions;
+pub mod trace;
+pub mod debug;
+pub mod decompile;
+mod nwscript {
+  include!(concat!(env!("OUT_DIR"), "/nwscript.rs"));
+}
+
+use std::collections::HashMap;
+use std::fmt;
+use std::string::String;
+
+pub use opcodes::{get_opcodes, get_nwtypes, parse_opcodes, OpcodeTableError};
+pub use engine::Engine;
+pub use disassemble::{disassemble, DisassemblyError};
+pub use assemble::{assemble, AssemblyError};
+pub use verify::{verify, VerifyError};
+pub use io_utils::{read_as_string, Encoding};
+pub use preprocess::{preprocess, PreprocessError};
+pub use resolve::resolve_constants;
+pub use types::{Literal, NWScriptType};
+pub use nwscript::ParseError;
+pub use error::Error;
+
+
+/// A constant declared in a definitions file, e.g. `int TRUE = 1;`.
+#[derive(Debug)]
+pub struct Constant {
+  pub nwtype: NWScriptType,
+  pub name: String,
+  pub value: Literal
+}
+
+/// An engine routine callable through `ACTION`, keyed by its routine code.
+#[derive(Debug)]
+pub struct Routine {
+  pub return_type: NWScriptType,
+  pub name: String,
+  pub code: u16,
+  pub args: Vec<RoutineArg>
+}
+
+#[derive(Debug)]
+pub struct RoutineArg {
+  pub nwtype: NWScriptType,
+  pub name: String,
+  pub default_value: Option<Literal>
+}
+
+/// A single top level declaration from a definitions file.
+#[derive(Debug)]
+pub enum Statement {
+  Routine(Routine),
+  Constant(Constant)
+}
+
+/// Parse the contents of a DA-style definitions file into a list of statements.
+pub fn parse_definitions(src: &str) -> Result<Vec<Statement>, ParseError> {
+  nwscript::document(src)
+}
+
+/// Parse an NWN-style `nwscript.nss`, where each routine's code is its position in the file.
+pub fn parse_nwn_definitions(src: &str) -> Result<Vec<Statement>, ParseError> {
+  let mut doc = try!(nwscript::nwn_document(src));
+  let mut code = 0;
+  for st in doc.iter_mut() {
+    if let Statement::Routine(ref mut r) = *st {
+      r.code = code;
+      code += 1;
+    }
+  }
+  Ok(doc)
+}
+
+/// Something wrong with a definitions file.
+#[derive(Debug)]
+pub enum DefinitionError {
+  Preprocess(PreprocessError),
+  Syntax(ParseError),
+  DuplicateConstant(Constant),
+  DuplicateRoutine(Routine),
+  UndefinedConstant(String, String), // the name, and where it was used
+  CyclicConstant(Vec<String>),
+  BadExpression(String, String)
+}
+
+impl fmt::Display for DefinitionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DefinitionError::Preprocess(ref e) => write!(f, "{}", e),
+      DefinitionError::Syntax(ref e) => write!(f, "{}", e),
+      DefinitionError::DuplicateConstant(ref d) => {
+        try!(writeln!(f, "Multiple declarations of variable {}", d.name));
+        write!(f, "     > {} {} = {};", d.nwtype, d.name, d.value)
+      },
+      DefinitionError::DuplicateRoutine(ref d) => {
+        try!(writeln!(f, "Multiple declarations of routine {}", d.name));
+        write!(f, "     > {} {}(...) = {};", d.return_type, d.name, d.code)
+      },
+      DefinitionError::UndefinedConstant(ref name, ref context) =>
+        write!(f, "Undefined constant {} in {}", name, context),
+      DefinitionError::CyclicConstant(ref names) =>
+        write!(f, "Constants defined in terms of themselves: {}", names.join(" -> ")),
+      DefinitionError::BadExpression(ref context, ref m) => write!(f, "{} in {}", m, context)
+    }
+  }
+}
+
+impl std::error::Error for DefinitionError {
+  fn description(&self) -> &str {
+    "invalid definitions"
+  }
+}
+
+impl From<PreprocessError> for DefinitionError {
+  fn from(e: PreprocessError) -> Self {
+    DefinitionError::Preprocess(e)
+  }
+}
+
+impl From<ParseError> for DefinitionError {
+  fn from(e: ParseError) -> Self {
+    DefinitionError::Syntax(e)
+  }
+}
+
+/// Split parsed definitions into a constant table keyed by name and a routine table keyed by
+/// routine code.
+///
+/// Fails if a constant name or routine code is declared more than once.
+pub fn build_tables(list: Vec<Statement>) -> Result<(HashMap<String, Constant>,
+                                                     HashMap<u16, Routine>), DefinitionError> {
+  let mut constants = HashMap::new();
+  let mut commands = HashMap::new(); // 16-bit, not sure if int or uint
+
+  for st in list {
+    match st {
+      Statement::Constant(c) => {
+        match constants.insert(c.name.clone(), c) {
+          Some(c) => return Err(DefinitionError::DuplicateConstant(c)),
+          None => ()
+        }
+      },
+      Statement::Routine(c) => {
+        // This does not handle duplicate names, which would matter for compiling
+        match commands.insert(c.code, c) {
+          Some(c) => return Err(DefinitionError::DuplicateRoutine(c)),
+          None => ()
+        }
+      }
+    }
+  }
+
+  Ok((constants, commands))
+}
+
+// A routine with unnamed arguments, for tests that need a routine table
+#[cfg(test)]
+fn test_routine(code: u16, name: &str, return_type: NWScriptType, args: Vec<NWScriptType>)
+                -> Routine {
+  let args = args.into_iter()
+    .map(|t| RoutineArg { nwtype: t, name: "x".to_string(), default_value: None })
+    .collect();
+  Routine { return_type: return_type, name: name.to_string(), code: code, args: args }
+}
+
+#[cfg(test)]
+mod nwscript_tests {
+  use nwscript;
+  use super::{build_tables, parse_definitions, parse_nwn_definitions, DefinitionError, Literal};
+
+  #[test]
+  fn function() {
+    assert!(nwscript::function("void foo() = 0;").is_ok());
+    assert!(nwscript::function("int foo(string x = \"\") = 10;").is_ok());
+    assert!(nwscript::function("int foo(string x = \"\") = 10;").is_ok());
+
+    assert!(nwscript::function("void foo();").is_err());
+  }
+
+  #[test]
+  fn line_comments() {
+    assert!(nwscript::line_comment("//this is a comment\n").is_ok());
+    assert!(nwscript::function("int foo(string x = \"\")//hi\n = 10;").is_ok());
+  }
+
+  #[test]
+  fn nwn_definitions() {
+    let src = "#define ENGINE_STRUCTURE_0 effect\n\
+               int TRUE = 1;\n\
+               object OBJECT_INVALID = 0x7F000000;\n\
+               // 0: Get an integer between 0 and nMaxInteger-1.\n\
+               int Random(int nMaxInteger);\n\
+               void DelayCommand(float fSeconds, action aActionToDelay);\n\
+               vector Vector(float x=0.0f, float y=0.0f, float z=0.0f);\n";
+    let (constants, routines) = build_tables(parse_nwn_definitions(src).unwrap()).unwrap();
+    assert_eq!(constants["OBJECT_INVALID"].value, Literal::Object(0x7F000000));
+    assert_eq!(routines[&0].name, "Random");
+    assert_eq!(routines[&2].name, "Vector");
+    assert!(nwscript::function("int Random(int nMaxInteger);").is_err());
+  }
+
+  #[test]
+  fn duplicate_definitions() {
+    let doc = parse_definitions("int A = 1;\nint A = 2;\n").unwrap();
+    match build_tables(doc) {
+      Err(DefinitionError::DuplicateConstant(c)) => assert_eq!(c.name, "A"),
+      _ => panic!("expected a duplicate constant")
+    }
+  }
+}
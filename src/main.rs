@@ -1,116 +1,110 @@
 extern crate docopt;
-extern crate byteorder;
+extern crate ox;
 #[macro_use]
 extern crate serde_derive;
 
 
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs::File;
-use std::io::prelude::*;
-use std::string::String;
-
-mod macros;
-mod opcodes;
-mod io_utils;
-mod disassemble;
-mod assemble;
-mod nwscript {
-    include!(concat!(env!("OUT_DIR"), "/nwscript.rs"));
-}
+use std::io::{Cursor, Read, Write, BufReader, BufWriter};
+use std::process;
 
 use docopt::Docopt;
-use io_utils::read_as_string;
-use disassemble::{disassemble, DisassemblyError};
-use assemble::AssemblyError;
-use nwscript::document;
-
-
-#[derive(Debug)]
-pub struct Constant {
-  type_name: String,
-  name: String,
-  value: String
-}
+use ox::engine::detect;
+use ox::cfg::{build_cfg, write_dot};
+use ox::stack::check_stack;
+use ox::actions::Registry;
+use ox::trace::{trace, TraceOptions};
+use ox::vm::Vm;
+use ox::debug::{repl, Debugger};
+use ox::decompile::decompile;
+use ox::disassemble::read_ops;
+use ox::{build_tables, resolve_constants, parse_definitions, parse_nwn_definitions, preprocess};
+use ox::{opcodes, read_as_string};
+use ox::{Constant, Routine, DefinitionError, Encoding, Engine, Error, verify};
+use ox::disassemble::{disassemble, DisassemblyOptions};
+use ox::assemble;
 
-#[derive(Debug)]
-pub struct Routine {
-  return_type: String,
-  name: String,
-  code: u16,
-  args: Vec<RoutineArg>
-}
-
-#[derive(Debug)]
-pub struct RoutineArg {
-  type_name: String,
-  name: String,
-  default_value: Option<String>
-}
-#[derive(Debug)]
-pub enum Statement {
-  Routine(Routine),
-  Constant(Constant)
-}
-
-fn build_tables(list: Vec<Statement>) -> (HashMap<String, Constant>, HashMap<u16, Routine>) {
-  let mut constants = HashMap::new();
-  let mut commands = HashMap::new(); // 16-bit, not sure if int or uint
-
-  for st in list {
-    match st {
-      Statement::Constant(c) => {
-        match constants.insert(c.name.clone(), c) {
-          Some(c) => {
-            let d = constants.get(&c.name).unwrap();
-            println_err!("Error: Multiple declarations of variable {}", d.name);
-            println_err!("     > {} {} = {};", d.type_name.trim(), d.name, d.value);
-            panic!("duplicate variable");
-          },
-          None => ()
-        }
-      },
-      Statement::Routine(c) => {
-        // This does not handle duplicate names, which would matter for compiling
-        match commands.insert(c.code, c) {
-          Some(c) => {
-            let d = commands.get(&c.code).unwrap();
-            println_err!("Error: Multiple declarations of routine {}", d.name);
-            println_err!("     > {} {}(...) = {};", d.return_type.trim(), d.name, d.code);
-            panic!("duplicate routine");
-          },
-          None => ()
-        }
-      }
-    }
-  }
-
-  return (constants, commands)
-}
 
 const USAGE: &'static str = "
-Usage: ox d <input> -c <def.ldf> [--nwn] [-o <output.ox>]
-       ox a <input> [-c <def.ldf> [--nwn]] [-o <output.ncs>]
+Usage: ox d <input> -c <def.ldf> [-D NAME]... [options]
+       ox a <input> [-c <def.ldf> [-D NAME]...] [options]
+       ox verify <input> [-c <def.ldf> [-D NAME]...] [options]
+       ox detect <ncs>...
+       ox cfg <input> [-c <def.ldf> [-D NAME]...] [options]
+       ox check <input> -c <def.ldf> [-D NAME]... [options]
+       ox trace <input> -c <def.ldf> [-D NAME]... [options]
+       ox debug <input> -c <def.ldf> [-D NAME]... [options]
+       ox decompile <input> -c <def.ldf> [-D NAME]... [options]
        ox --help
 
 Options:
   d <input.ox>            Disassemble input.ncs file.
   a <input.ncs>           Assemble input.ox file.
+  verify <input.ncs>      Check that input.ncs disassembles and reassembles unchanged.
+  detect <ncs>...         Report which engine each file most likely comes from.
+  cfg <input.ncs>         Write the control-flow graph of input.ncs in Graphviz DOT format.
+  check <input.ncs>       Check that input.ncs keeps the stack consistent on every path.
+  trace <input.ncs>       Run input.ncs with stub engine routines, logging each instruction.
+  debug <input.ncs>       Step through input.ncs interactively. Type help for commands.
+  decompile <input.ncs>   Rebuild NWScript source for input.ncs.
 
   -c, --define DFILE      Engine routine definition file.
-  --nwn                   Expect NWN-style routine definitions.
+  -D, --macro NAME        Define NAME (or NAME=VALUE) for the definitions preprocessor.
+  --engine ENGINE         The game the script is from: nwn, nwn2, kotor, dao, da2, auto
+                          to detect it, or generic for a table mixing all of them. The
+                          dao and da2 structure names after location are provisional.
+                          [default: generic]
+  --opcodes TABLE         Load the opcode table from a file instead of the built-in one.
+  --nwn                   Expect NWN-style routine definitions, numbered by position.
+                          Implied by --engine nwn, nwn2 and kotor.
+  --labels                Print jump targets as generated labels instead of offsets.
+  --listing               Prefix instructions with their file offset and raw bytes.
+  --functions             Group instructions by subroutine, with headers and call counts.
+  --json                  Trace as JSON lines instead of text.
+  --top N                 How many stack values to trace. [default: 4]
+  --seed SEED             Seed for Random and the dice routines. [default: 0]
+  --steps N               Stop a trace, or a debugger continue, after N instructions.
+                          [default: 1000000]
+  -e, --encoding ENC      String encoding: utf-8, windows-1252 or latin-1.
+                          [default: utf-8]
   -o, --output OUTPUT     The file to write output to.
   -h, --help              Show this message.
+
+Exit status:
+  0 on success, 1 for bad arguments, 2 for I/O errors, 3 for invalid definitions or
+  opcode tables, 4 if the input can't be disassembled, 5 if it can't be assembled,
+  6 if verify finds a difference, 7 if check finds a stack problem and 8 if the script
+  fails when run.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
   cmd_d: bool,
   cmd_a: bool,
+  cmd_verify: bool,
+  cmd_detect: bool,
+  cmd_cfg: bool,
+  cmd_check: bool,
+  cmd_trace: bool,
+  cmd_debug: bool,
+  cmd_decompile: bool,
   arg_input: String,
+  arg_ncs: Vec<String>,
   flag_define: String,
+  flag_macro: Vec<String>,
   flag_output: String,
+  flag_engine: String,
+  flag_opcodes: String,
   flag_nwn: bool,
+  flag_labels: bool,
+  flag_listing: bool,
+  flag_functions: bool,
+  flag_json: bool,
+  flag_top: usize,
+  flag_seed: u64,
+  flag_steps: usize,
+  flag_encoding: String,
 }
 
 // gold-plating: tabs/spaces, hex options, cyclic (-r?) option that is -d then -a or vice versa
@@ -124,22 +118,121 @@ fn main() {
     .and_then(|d| d.deserialize())
     .unwrap_or_else(|e| e.exit());
 
-  let opcodes = opcodes::get_opcodes();
+  match run(&args) {
+    Ok(_) => (),
+    Err(e) => {
+      eprintln!("Error: {}", e);
+      process::exit(e.exit_code());
+    }
+  }
+}
+
+fn open_input(path: &String) -> Result<BufReader<File>, Error> {
+  match File::open(path) {
+    Ok(f) => Ok(BufReader::new(f)),
+    Err(e) => Err(Error::IO(path.clone(), e))
+  }
+}
+
+fn read_input(path: &String) -> Result<Vec<u8>, Error> {
+  let mut data = vec!();
+  try!(try!(open_input(path)).read_to_end(&mut data).map_err(|e| Error::IO(path.clone(), e)));
+  Ok(data)
+}
+
+fn open_output(path: &String) -> Result<BufWriter<Box<Write>>, Error> {
+  if "" == path {
+    return Ok(BufWriter::new(Box::new(std::io::stdout())));
+  }
+  match File::create(path) {
+    Ok(f) => Ok(BufWriter::new(Box::new(f))),
+    Err(e) => Err(Error::IO(path.clone(), e))
+  }
+}
+
+fn load_tables(def_path: &String, macros: &[String], nwn: bool)
+               -> Result<(HashMap<String, Constant>, HashMap<u16, Routine>), Error> {
+  // Read the definitions file
+  let src = match read_as_string(def_path) {
+    Err(e) => return Err(Error::IO(def_path.clone(), e)),
+    Ok(s) => s
+  };
+
+  // Expand #define, #include and #if
+  let mut defines = HashMap::new();
+  for m in macros {
+    let mut parts = m.splitn(2, '=');
+    let name = parts.next().unwrap().to_string();
+    defines.insert(name, parts.next().unwrap_or("1").to_string());
+  }
+  let res = try!(preprocess(&src, def_path, &mut defines)
+                 .map_err(|e| Error::Definitions(def_path.clone(), DefinitionError::from(e))));
+
+  // Parse definitions with peg
+  let parse = if nwn { parse_nwn_definitions } else { parse_definitions };
+  let doc = match parse(res.as_ref()) {
+    Err(e) => return Err(Error::Definitions(def_path.clone(), DefinitionError::from(e))),
+    Ok(d) => d
+  };
 
-  let doc = if args.flag_define.len() > 0 {
-    let def_path = &args.flag_define;
+  let (mut constants, mut routines) = try!(build_tables(doc)
+                                           .map_err(|e| Error::Definitions(def_path.clone(), e)));
+  try!(resolve_constants(&mut constants, &mut routines)
+       .map_err(|e| Error::Definitions(def_path.clone(), e)));
+  Ok((constants, routines))
+}
 
-    // Read the definitions file
-    let res = match read_as_string(def_path) {
-      Err(e) => panic!("{}", e),
-      Ok(s) => s
-    };
+fn run(args: &Args) -> Result<(), Error> {
+  // Detect
+  if args.cmd_detect {
+    for path in args.arg_ncs.iter() {
+      match detect(&try!(read_input(path))) {
+        Ok(d) => println!("{}: {}", path, d),
+        Err(e) => println!("{}: no engine fits: {}", path, e)
+      }
+    }
+    return Ok(())
+  }
 
-    // Parse definitions with peg
-    match document(res.as_ref()) { // TODO nwn mode
-      Err(e) => panic!("{}", e),
-      Ok(d) => Some(d)
+  // The compiled script, which --engine auto needs before anything else
+  let data = if args.cmd_d || args.cmd_verify || args.cmd_cfg || args.cmd_check ||
+    args.cmd_trace || args.cmd_debug || args.cmd_decompile {
+    Some(try!(read_input(&args.arg_input)))
+  } else {
+    None
+  };
+  let engine = match (args.flag_engine.as_ref(), data.as_ref()) {
+    ("auto", Some(data)) => {
+      let d = try!(detect(data).map_err(|e| Error::Disassembly(args.arg_input.clone(), e)));
+      eprintln!("Detected engine {} ({:.0}%)", d.best(), d.candidates[0].confidence * 100.0);
+      d.best()
+    },
+    ("auto", None) =>
+      return Err(Error::Usage("--engine auto needs a compiled script".to_string())),
+    (name, _) => match Engine::from_name(name) {
+      Some(e) => e,
+      None => return Err(Error::Usage(format!("Unknown engine {}", name)))
+    }
+  };
+  let opcodes = if args.flag_opcodes.len() > 0 {
+    if engine != Engine::Generic {
+      return Err(Error::Usage("--opcodes replaces the --engine opcode table".to_string()));
     }
+    let path = &args.flag_opcodes;
+    let src = try!(read_as_string(path).map_err(|e| Error::IO(path.clone(), e)));
+    try!(opcodes::parse_opcodes(&src).map_err(|e| Error::Opcodes(path.clone(), e)))
+  } else {
+    engine.opcodes()
+  };
+  let encoding = match Encoding::from_name(&args.flag_encoding) {
+    Some(e) => e,
+    None => return Err(Error::Usage(format!("Unknown encoding {}", args.flag_encoding)))
+  };
+
+  let tables = if args.flag_define.len() > 0 {
+    // a detected engine is only a guess, so it doesn't get to change how definitions parse
+    let nwn = args.flag_nwn || (args.flag_engine != "auto" && engine.nwn_definitions());
+    Some(try!(load_tables(&args.flag_define, &args.flag_macro, nwn)))
   } else {
     None
   };
@@ -147,82 +240,121 @@ fn main() {
   // Assemble
   if args.cmd_a {
     let asm_path = &args.arg_input;
-    let output_path = if "" == args.flag_output { None } else { Some(&args.flag_output) };
-
-    let rdr = std::io::BufReader::new(match File::open(asm_path){
-      Ok(f) => f,
-      Err(reason) => panic!("Opening {} failed: {}", asm_path, Error::description(&reason))
-    });
-
-    if doc.is_some() {
-      let (_, routines) = build_tables(doc.unwrap());
-
-      match assemble::assemble(rdr, &opcodes, Some(&routines), output_path) {
-        Ok(_) => println!("Assembly complete, no defs"),
-        Err(e) => match e {
-          AssemblyError::ParseError(m) => panic!("Assembly failed: {}", m),
-          AssemblyError::IOError(e) => panic!("Assembly failed: {}", e),
-          // TODO fix I/O error handling
-        }
-      }
-    } else {
-      match assemble::assemble(rdr, &opcodes, None, output_path) {
-        Ok(_) => println!("Assembly complete, no defs"),
-        Err(e) => match e {
-          AssemblyError::ParseError(m) => panic!("Assembly failed: {}", m),
-          AssemblyError::IOError(e) => panic!("Assembly failed: {}", e),
-          // TODO fix I/O error handling
-        }
-      }
-    }
-
-    return
+    let rdr = try!(open_input(asm_path));
+    let mut wtr = try!(open_output(&args.flag_output));
+    let routines = tables.as_ref().map(|t| &t.1);
+
+    try!(assemble::assemble(rdr, &mut wtr, &opcodes, routines, engine, encoding)
+         .map_err(|e| Error::Assembly(asm_path.clone(), e)));
+    try!(wtr.flush().map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    eprintln!("Assembly complete");
+    return Ok(())
   }
 
   // Disassemble
   if args.cmd_d {
-    let output_path = if "" == args.flag_output { None } else { Some(&args.flag_output) };
-
     // Build tables
-    let (constants, routines) = build_tables(doc.unwrap());
-    // TODO stick this at the front of the writer? pass the writer in to fn instead?
-    println!(";;Read {} constants and {} routines", constants.len(), routines.len());
+    let (constants, routines) = tables.unwrap();
 
-    // Read the compiled file
     let asm_path = &args.arg_input; // TODO stream this instead
-    let mut rdr = std::io::BufReader::new(match File::open(&asm_path){
-      Ok(f) => f,
-      Err(reason) => panic!("Opening {} failed: {}", &asm_path, Error::description(&reason))
-    });
-
-    match disassemble(&mut rdr, &opcodes, &routines, output_path) {
-      Ok(_) => (),
-      Err(e) => match e {
-        DisassemblyError::OpStreamError(m, b) => panic!("Disassembly failed: {} (byte {})", m, b),
-        _ => panic!("Disassembly failed ???") // TODO fix error handling
-      }
-    }
+    let mut rdr = Cursor::new(data.unwrap());
+    let mut wtr = try!(open_output(&args.flag_output));
+    try!(writeln!(wtr, ";;Read {} constants and {} routines", constants.len(), routines.len())
+         .map_err(|e| Error::IO(args.flag_output.clone(), e)));
+
+    let options = DisassemblyOptions { labels: args.flag_labels, listing: args.flag_listing,
+                                       functions: args.flag_functions, encoding: encoding,
+                                       engine: engine };
+    try!(disassemble(&mut rdr, &mut wtr, &opcodes, &routines, &options)
+         .map_err(|e| Error::Disassembly(asm_path.clone(), e)));
+    try!(wtr.flush().map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    return Ok(())
+  }
 
-    return
+  // Round trip
+  if args.cmd_verify {
+    let path = &args.arg_input;
+    let data = data.unwrap();
+    let routines = tables.map_or(HashMap::new(), |t| t.1);
+
+    let options = DisassemblyOptions { labels: args.flag_labels, functions: args.flag_functions,
+                                       encoding: encoding, engine: engine, ..Default::default() };
+    try!(verify(&data, &opcodes, &routines, &options).map_err(|e| Error::Verify(path.clone(), e)));
+    println!("{}: OK", path);
+    return Ok(())
   }
-}
 
-#[cfg(test)]
-mod nwscript_tests {
-  use nwscript;
+  // Control flow
+  if args.cmd_cfg {
+    let path = &args.arg_input;
+    let routines = tables.map_or(HashMap::new(), |t| t.1);
+    let (_, ops) = try!(read_ops(&mut Cursor::new(data.unwrap()), &opcodes)
+                        .map_err(|e| Error::Disassembly(path.clone(), e)));
+    let mut wtr = try!(open_output(&args.flag_output));
+    try!(write_dot(&mut wtr, &build_cfg(&ops), &ops, &opcodes, &routines, engine, encoding)
+         .map_err(|e| Error::Disassembly(path.clone(), e)));
+    try!(wtr.flush().map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    return Ok(())
+  }
 
-  #[test]
-  fn function() {
-    assert!(nwscript::function("void foo() = 0;").is_ok());
-    assert!(nwscript::function("int foo(string x = \"\") = 10;").is_ok());
-    assert!(nwscript::function("int foo(string x = \"\") = 10;").is_ok());
+  // Stack depth
+  if args.cmd_check {
+    let path = &args.arg_input;
+    let (_, routines) = tables.unwrap();
+    let (_, ops) = try!(read_ops(&mut Cursor::new(data.unwrap()), &opcodes)
+                        .map_err(|e| Error::Disassembly(path.clone(), e)));
+    let report = check_stack(&ops, &build_cfg(&ops), &routines);
+    if report.problems.len() > 0 {
+      return Err(Error::Stack(path.clone(), report.problems));
+    }
+    println!("{}: OK", path);
+    return Ok(())
+  }
 
-    assert!(nwscript::function("void foo();").is_err());
+  // Source
+  if args.cmd_decompile {
+    let path = &args.arg_input;
+    let (_, routines) = tables.unwrap();
+    let (_, ops) = try!(read_ops(&mut Cursor::new(data.unwrap()), &opcodes)
+                        .map_err(|e| Error::Disassembly(path.clone(), e)));
+    let mut wtr = try!(open_output(&args.flag_output));
+    try!(decompile(&mut wtr, &ops, &routines, engine, encoding)
+         .map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    try!(wtr.flush().map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    return Ok(())
   }
 
-  #[test]
-  fn line_comments() {
-    assert!(nwscript::line_comment("//this is a comment\n").is_ok());
-    assert!(nwscript::function("int foo(string x = \"\")//hi\n = 10;").is_ok());
+  // Run
+  if args.cmd_trace {
+    let path = &args.arg_input;
+    let (_, routines) = tables.unwrap();
+    let (_, ops) = try!(read_ops(&mut Cursor::new(data.unwrap()), &opcodes)
+                        .map_err(|e| Error::Disassembly(path.clone(), e)));
+    let mut registry = Registry::with_library(engine, args.flag_seed, &routines);
+    let mut vm = Vm::new(&ops, &routines, encoding);
+    let mut wtr = try!(open_output(&args.flag_output));
+    let options = TraceOptions { json: args.flag_json, top: args.flag_top,
+                                 steps: args.flag_steps, encoding: encoding };
+    try!(trace(&mut wtr, &mut vm, &mut registry, &opcodes, &routines, &options)
+         .map_err(|e| Error::Trace(path.clone(), e)));
+    try!(wtr.flush().map_err(|e| Error::IO(args.flag_output.clone(), e)));
+    return Ok(())
   }
+
+  // Debug
+  if args.cmd_debug {
+    let path = &args.arg_input;
+    let (_, routines) = tables.unwrap();
+    let (_, ops) = try!(read_ops(&mut Cursor::new(data.unwrap()), &opcodes)
+                        .map_err(|e| Error::Disassembly(path.clone(), e)));
+    let registry = Registry::with_library(engine, args.flag_seed, &routines);
+    let mut debugger = Debugger::new(&ops, &opcodes, &routines, registry, encoding);
+    debugger.steps = args.flag_steps;
+    let stdin = std::io::stdin();
+    try!(repl(&mut stdin.lock(), &mut std::io::stdout(), &mut debugger)
+         .map_err(|e| Error::IO("<stdin>".to_string(), e)));
+    return Ok(())
+  }
+
+  Ok(())
 }
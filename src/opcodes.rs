@@ -1,6 +1,10 @@
 use std::iter::repeat;
 use std::collections::{HashMap,HashSet};
+use std::error::Error;
 use std::fmt;
+use std::string::String; // the Operand variant is only a value
+
+use io_utils::{bytes_to_int, bytes_to_uint};
 use self::Operand::*;
 
 
@@ -58,6 +62,33 @@ pub enum OpcodeE {
   T = 0x42,
 }
 
+const ALL_OPCODES: [OpcodeE; 47] = [
+  OpcodeE::CPDOWNSP, OpcodeE::RSADD, OpcodeE::CPTOPSP, OpcodeE::CONST, OpcodeE::ACTION,
+  OpcodeE::LOGANDII, OpcodeE::LOGORII, OpcodeE::INCORII, OpcodeE::EXCORII, OpcodeE::BOOLANDII,
+  OpcodeE::EQUAL, OpcodeE::NEQUAL, OpcodeE::GEQ, OpcodeE::GT, OpcodeE::LT, OpcodeE::LEQ,
+  OpcodeE::SHLEFTII, OpcodeE::SHRIGHTII, OpcodeE::USHRIGHTII, OpcodeE::ADD, OpcodeE::SUB,
+  OpcodeE::MUL, OpcodeE::DIV, OpcodeE::MODII, OpcodeE::NEG, OpcodeE::COMPI, OpcodeE::MOVSP,
+  OpcodeE::STORE_STATEALL, OpcodeE::JMP, OpcodeE::JSR, OpcodeE::JZ, OpcodeE::RETN,
+  OpcodeE::DESTRUCT, OpcodeE::NOTI, OpcodeE::DECISP, OpcodeE::INCISP, OpcodeE::JNZ,
+  OpcodeE::CPDOWNBP, OpcodeE::CPTOPBP, OpcodeE::DECIBP, OpcodeE::INCIBP, OpcodeE::SAVEBP,
+  OpcodeE::RESTOREBP, OpcodeE::STORE_STATE, OpcodeE::NOP, OpcodeE::CP_x37_DA2_QQ, OpcodeE::T
+];
+
+impl OpcodeE {
+  /// Look up an opcode by its mnemonic, e.g. `JMP`.
+  pub fn from_name(name: &str) -> Option<OpcodeE> {
+    ALL_OPCODES.iter().find(|o| o.to_string() == name).map(|o| *o)
+  }
+
+  /// Whether the opcode transfers control to a relative `Offset` operand.
+  pub fn is_jump(&self) -> bool {
+    match *self {
+      OpcodeE::JMP | OpcodeE::JSR | OpcodeE::JZ | OpcodeE::JNZ => true,
+      _ => false
+    }
+  }
+}
+
 impl fmt::UpperHex for OpcodeE {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "{:X}", *self as usize)
@@ -90,21 +121,42 @@ pub enum Operand {
   ArgCount(usize),
 }
 
-// TODO output start-of-opcode byte indices
-// TODO output some info about jump targets
 pub struct OpPayload<'a > {
+  pub offset: usize,
   pub bytes_read: usize,
   pub op: &'a Opcode,
   pub _type: Option<u8>,
   pub args: Vec<(&'a Operand, Vec<u8>)>
 } // find some way to implement Show with an instance payload type?
 
+impl<'a> OpPayload<'a> {
+  /// The instruction's bytes as they appear in the NCS file.
+  pub fn encoded(&self) -> Vec<u8> {
+    let mut bytes = vec!(self.op.code as u8);
+    bytes.extend(self._type.iter());
+    for &(_, ref arg) in self.args.iter() {
+      bytes.extend(arg.iter());
+    }
+    bytes
+  }
+
+  /// The offset, size, routine and argument count operands, in order. Offsets are signed.
+  pub fn numbers(&self) -> Vec<i32> {
+    self.args.iter().filter_map(|&(arg, ref bytes)| match *arg {
+      Offset(..) => bytes_to_int(bytes).ok(),
+      Size(..) | Routine(..) | ArgCount(..) => bytes_to_uint(bytes).ok().map(|n| n as i32),
+      _ => None
+    }).collect()
+  }
+}
+
 #[derive(Debug)]
 #[derive(Clone,Copy)]
 pub enum NWTypeE {
   // unknown types
   NULLQ = 0x00,
   COPYQ = 0x01,
+  StateAllQ = 0x08,
 
   // unary types
   I = 0x03,
@@ -117,6 +169,12 @@ pub enum NWTypeE {
   Event = 0x11,
   Location = 0x12,
   Talent = 0x13,
+  Unknownx14 = 0x14,
+  Unknownx15 = 0x15,
+  Unknownx16 = 0x16,
+  Unknownx17 = 0x17,
+  Unknownx18 = 0x18,
+  Unknownx19 = 0x19,
 
   // binary types
   II = 0x20,
@@ -171,15 +229,6 @@ macro_rules! opcode {
   ($c:expr, $f:expr, $t:expr, $a:expr) => {Opcode { code: $c, fmt: $f, types: $t, args: $a}}
 }
 
-macro_rules! hashmap {
-    ($( $key: expr => $val: expr ),*) => {{
-         let mut map = HashMap::new();
-         $( map.insert($key, $val); )*
-         map
-    }}
-}
-
-
 #[allow(dead_code)] // bug in Rust with constants that are not used in impls
 const MAX_OPCODES: usize = 256; // 0xFF
 const MAX_STYPES: usize = 61; // 0x3D
@@ -195,117 +244,182 @@ const MAX_STYPES: usize = 61; // 0x3D
 };*/
 
 
-pub fn get_opcodes() -> Box<[Option<Opcode>]> {
-  use self::OpcodeE::*;
+/// A problem in an opcode table, with the line it was found on.
+#[derive(Debug)]
+pub struct OpcodeTableError {
+  pub line: usize,
+  pub message: String
+}
+
+impl fmt::Display for OpcodeTableError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+impl Error for OpcodeTableError {
+  fn description(&self) -> &str {
+    "invalid opcode table"
+  }
+}
+
+const BUILTIN_OPCODES: &'static str = include_str!("opcodes.tbl");
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+  if !s.starts_with("0x") {
+    return Err(format!("Expected a hex byte like 0x1F, got {}", s));
+  }
+  u8::from_str_radix(&s[2..], 16).map_err(|e| format!("{}: {}", s, e))
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+  if s == "string" {
+    return Ok(Operand::String);
+  }
+  let mut parts = s.splitn(2, ':');
+  let kind = parts.next().unwrap();
+  let size = match parts.next().map(|n| n.parse::<usize>()) {
+    Some(Ok(n)) => n,
+    _ => return Err(format!("Expected an operand like offset:4, got {}", s))
+  };
+  let (operand, sizes): (Operand, &[usize]) = match kind {
+    "routine" => (Routine(size), &[2]),
+    "object" => (Object(size), &[4]),
+    "size" => (Size(size), &[2, 4]),
+    "offset" => (Offset(size), &[2, 4]),
+    "integer" => (Integer(size), &[4]),
+    "float" => (Float(size), &[4]),
+    "argcount" => (ArgCount(size), &[1]),
+    _ => return Err(format!("Unknown operand kind {}", kind))
+  };
+  if !sizes.contains(&size) {
+    return Err(format!("{} operands can't be {} bytes", kind, size));
+  }
+  Ok(operand)
+}
+
+// Check that literal operands agree with the type byte they're listed under
+fn check_operands(t: u8, operands: &[Operand]) -> Result<(), String> {
+  for (n, operand) in operands.iter().enumerate() {
+    let (needed, name) = match *operand {
+      Integer(_) => (NWTypeE::I, "integer"),
+      Float(_) => (NWTypeE::F, "float"),
+      Object(_) => (NWTypeE::O, "object"),
+      Operand::String => {
+        match n.checked_sub(1).map(|p| &operands[p]) {
+          Some(&Size(2)) => (),
+          _ => return Err("string operands must follow a size:2 operand".to_string())
+        }
+        (NWTypeE::S, "string")
+      },
+      _ => continue
+    };
+    if t != needed as u8 {
+      return Err(format!("{} operand under type {:#04X}", name, t));
+    }
+  }
+  Ok(())
+}
+
+fn parse_entry(line: &str, nwtypes: &[Option<NWType>]) -> Result<Opcode, String> {
+  let mut fields = line.split_whitespace();
+  let name = fields.next().unwrap();
+  let code = match OpcodeE::from_name(name) {
+    Some(c) => c,
+    None => return Err(format!("Unknown opcode {}", name))
+  };
+  let byte = try!(parse_byte(try!(fields.next().ok_or(format!("{} has no opcode byte", name)))));
+  if byte != code as u8 {
+    return Err(format!("{} is opcode {:#04X}, not {:#04X}", name, code as u8, byte));
+  }
+
+  let rest: Vec<&str> = fields.collect();
+  if rest.len() > 0 && rest[0] == "-" {
+    let operands = try!(rest[1..].iter().map(|o| parse_operand(o)).collect());
+    let mut args = HashMap::new();
+    args.insert(0x00, operands);
+    return Ok(Opcode { code: code, types: None, args: Some(args) });
+  }
+
+  let mut types = vec!();
+  let mut args = HashMap::new();
+  for variant in rest.split(|f| *f == "|") {
+    if variant.len() == 0 {
+      return Err(format!("{} has an empty type", name));
+    }
+    let t = try!(parse_byte(variant[0]));
+    if nwtypes.get(t as usize).map_or(true, |n| n.is_none()) {
+      return Err(format!("{} has unknown type {:#04X}", name, t));
+    }
+    let operands: Vec<Operand> = try!(variant[1..].iter().map(|o| parse_operand(o)).collect());
+    try!(check_operands(t, &operands).map_err(|e| format!("{}: {}", name, e)));
+    if types.contains(&t) {
+      return Err(format!("{} lists type {:#04X} more than once", name, t));
+    }
+    types.push(t);
+    args.insert(t, operands);
+  }
+
+  // Opcodes without operands don't carry an argument map at all
+  let args = if args.values().any(|a| a.len() > 0) { Some(args) } else { None };
+  Ok(Opcode { code: code, types: Some(types), args: args })
+}
+
+/// Parse an opcode table in the format of the built-in `opcodes.tbl`: one line per opcode
+/// giving its mnemonic, opcode byte, legal types and operands for each type. Lines that start
+/// with whitespace continue the previous entry and `#` starts a comment.
+///
+/// Fails on unknown opcodes or types, duplicate entries, and operands that don't fit the type
+/// they're listed under.
+pub fn parse_opcodes(src: &str) -> Result<Box<[Option<Opcode>]>, OpcodeTableError> {
   // Take N * None without Clone or Copy
-  let mut x:Vec<Option<Opcode>> = repeat(true).take(MAX_OPCODES).map(|_| None).collect();
-  // TODO fix python generation of codes for new format
-  // TODO auto insertion of generated values?
-  // TODO automatically check for duplicate keys
-  x[CPDOWNSP as usize] = Some(Opcode{ code: CPDOWNSP, types: Some(vec!(0x01)),
-                                      args: Some(hashmap!(0x01 => vec!(Offset(4), Size(2)))) });
-  x[RSADD as usize] = Some(Opcode{ code: RSADD,
-                                   types: Some(vec!(0x03, 0x04, 0x05, 0x06, 0x13)), args: None });
-  x[CPTOPSP as usize] = Some(Opcode{ code: CPTOPSP, types: Some(vec!(0x01)),
-                                     args: Some(hashmap!(0x01 => vec!(Offset(4), Size(2)))) });
-  x[CONST as usize] = Some(Opcode{ code: CONST, types: Some(vec!(0x03, 0x04, 0x05, 0x06)),
-                                   args: Some(hashmap!(0x03 => vec!(Integer(4)),
-                                                       0x04 => vec!(Float(4)),
-                                                       0x05 => vec!(Size(2), String),
-                                                       0x06 => vec!(Object(4)))) });
-  x[ACTION as usize] = Some(Opcode{ code: ACTION, types: Some(vec!(0x00)),
-                                    args: Some(hashmap!(0x00 => vec!(Routine(2), ArgCount(1)))) });
-  x[LOGANDII as usize] = Some(Opcode{ code: LOGANDII, types: Some(vec!(0x20)), args: None });
-  x[LOGORII as usize] = Some(Opcode{ code: LOGORII, types: Some(vec!(0x20)), args: None });
-  x[INCORII as usize] = Some(Opcode{ code: INCORII, types: Some(vec!(0x20)), args: None });
-  x[EXCORII as usize] = Some(Opcode{ code: EXCORII, types: Some(vec!(0x20)), args: None });
-  x[BOOLANDII as usize] = Some(Opcode{ code: BOOLANDII, types: Some(vec!(0x20)), args: None });
-  x[EQUAL as usize] = Some(Opcode{ code: EQUAL,
-                                   types: Some(vec!(0x20, 0x21, 0x22, 0x23, 0x024, 0x30, 0x31,
-                                                    0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38,
-                                                    0x39)),
-                                   args: Some(hashmap!(0x20 => vec!(), 0x21 => vec!(),
-                                                       0x22 => vec!(), 0x23 => vec!(),
-                                                       0x24 => vec!(), 0x30 => vec!(),
-                                                       0x31 => vec!(), 0x32 => vec!(),
-                                                       0x33 => vec!(), 0x34 => vec!(),
-                                                       0x35 => vec!(), 0x36 => vec!(),
-                                                       0x37 => vec!(), 0x38 => vec!(),
-                                                       0x39 => vec!(), 0x24 => vec!(Size(2)))) });
-  x[NEQUAL as usize] = Some(Opcode{ code: NEQUAL,
-                                    types: Some(vec!(0x20, 0x21, 0x22, 0x23, 0x30, 0x31, 0x32,
-                                                     0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39)),
-                                    args: Some(hashmap!(0x20 => vec!(), 0x21 => vec!(),
-                                                        0x22 => vec!(), 0x23 => vec!(),
-                                                        0x24 => vec!(), 0x30 => vec!(),
-                                                        0x31 => vec!(), 0x32 => vec!(),
-                                                        0x33 => vec!(), 0x34 => vec!(),
-                                                        0x35 => vec!(), 0x36 => vec!(),
-                                                        0x37 => vec!(), 0x38 => vec!(),
-                                                        0x39 => vec!(), 0x24 => vec!(Size(2)))) });
-  x[GEQ as usize] = Some(Opcode{ code: GEQ, types: Some(vec!(0x20, 0x21)), args: None });
-  x[GT as usize] = Some(Opcode{ code: GT, types: Some(vec!(0x20, 0x21)), args: None });
-  x[LT as usize] = Some(Opcode{ code: LT, types: Some(vec!(0x20, 0x21)), args: None });
-  x[LEQ as usize] = Some(Opcode{ code: LEQ, types: Some(vec!(0x20, 0x21)), args: None });
-  x[SHLEFTII as usize] = Some(Opcode{ code: SHLEFTII, types: Some(vec!(0x20)), args: None });
-  x[SHRIGHTII as usize] = Some(Opcode{ code: SHRIGHTII, types: Some(vec!(0x20)), args: None });
-  x[USHRIGHTII as usize] = Some(Opcode{ code: USHRIGHTII, types: Some(vec!(0x20)), args: None });
-  x[ADD as usize] = Some(Opcode{ code: ADD, types: Some(vec!(0x20, 0x25, 0x26, 0x21, 0x23, 0x3a)),
-                                 args: None });
-  x[SUB as usize] = Some(Opcode{ code: SUB,
-                                 types: Some(vec!(0x20, 0x25, 0x26, 0x21, 0x3a)), args: None });
-  x[MUL as usize] = Some(Opcode{ code: MUL, types: Some(vec!(0x20, 0x25, 0x26, 0x21, 0x3b, 0x3c)),
-                                 args: None });
-  x[DIV as usize] = Some(Opcode{ code: DIV,
-                                 types: Some(vec!(0x20, 0x25, 0x26, 0x21, 0x3b)), args: None });
-  x[MODII as usize] = Some(Opcode{ code: MODII, types: Some(vec!(0x20)), args: None });
-  x[NEG as usize] = Some(Opcode{ code: NEG, types: Some(vec!(0x03, 0x04)), args: None });
-  x[COMPI as usize] = Some(Opcode{ code: COMPI, types: Some(vec!(0x03)), args: None });
-  x[MOVSP as usize] = Some(Opcode{ code: MOVSP, types: Some(vec!(0x00)),
-                                   args: Some(hashmap!(0x00 => vec!(Offset(4)))) });
-  x[STORE_STATEALL as usize] = Some(Opcode{ code: STORE_STATEALL,
-                                            types: Some(vec!(0x08)), args: None });
-  x[JMP as usize] = Some(Opcode{ code: JMP, types: Some(vec!(0x00)),
-                                 args: Some(hashmap!(0x00 => vec!(Offset(4)))) });;
-  x[JSR as usize] = Some(Opcode{ code: JSR, types: Some(vec!(0x00)),
-                                 args: Some(hashmap!(0x00 => vec!(Offset(4)))) });
-  x[JZ as usize] = Some(Opcode{ code: JZ, types: Some(vec!(0x00)),
-                                args: Some(hashmap!(0x00 => vec!(Offset(4)))) });
-  x[RETN as usize] = Some(Opcode{ code: RETN, types: Some(vec!(0x00)), args: None });
-  x[DESTRUCT as usize] = Some(Opcode{ code: DESTRUCT, types: Some(vec!(0x01)),
-                                      args: Some(hashmap!(0x01 =>
-                                                          vec!(Size(2), Offset(2), Size(2)))) });
-  x[NOTI as usize] = Some(Opcode{ code: NOTI, types: Some(vec!(0x03)), args: None });
-  x[DECISP as usize] = Some(Opcode{ code: DECISP, types: Some(vec!(0x03)),
-                                    args: Some(hashmap!(0x03 => vec!(Offset(4)))) });
-  x[INCISP as usize] = Some(Opcode{ code: INCISP, types: Some(vec!(0x03)),
-                                    args: Some(hashmap!(0x03 => vec!(Offset(4)))) });
-  x[JNZ as usize] = Some(Opcode{ code: JNZ, types: Some(vec!(0x00)),
-                                 args: Some(hashmap!(0x00 => vec!(Offset(4)))) });
-  x[CPDOWNBP as usize] = Some(Opcode{ code: CPDOWNBP, types: Some(vec!(0x01)),
-                                      args: Some(hashmap!(0x01 => vec!(Offset(4), Size(2)))) });
-  x[CPTOPBP as usize] = Some(Opcode{ code: CPTOPBP, types: Some(vec!(0x01)),
-                                     args: Some(hashmap!(0x01 => vec!(Offset(4), Size(2)))) });
-  x[DECIBP as usize] = Some(Opcode{ code: DECIBP, types: Some(vec!(0x03)),
-                                    args: Some(hashmap!(0x03 => vec!(Offset(4)))) });
-  x[INCIBP as usize] = Some(Opcode{ code: INCIBP, types: Some(vec!(0x03)),
-                                    args: Some(hashmap!(0x03 => vec!(Offset(4)))) });
-  x[SAVEBP as usize] = Some(Opcode{ code: SAVEBP, types: Some(vec!(0x00)), args: None });
-  x[RESTOREBP as usize] = Some(Opcode{ code: RESTOREBP, types: Some(vec!(0x00)), args: None });
-  x[STORE_STATE as usize] = Some(Opcode{ code: STORE_STATE, types: Some(vec!(0x10)),
-                                         args: Some(hashmap!(0x10 => vec!(Size(4), Size(4)))) });
-  x[NOP as usize] = Some(Opcode{ code: NOP, types: Some(vec!(0x00)), args: None });
-  x[CP_x37_DA2_QQ as usize] = Some(Opcode{ code: CP_x37_DA2_QQ, // PROBABLY PARTIALLY INCORRECT!
-                                           types: Some(vec!(0x01)),
-                                           args: Some(hashmap!(0x01 => vec!(Offset(4), Size(2))))}); // MADE UP
-  x[T as usize] = Some(Opcode{ code: T, types: None,
-                               args: Some(hashmap!(0x00 => vec!(Size(4)))) }); // hack
-
-  // Does this actually need to be boxed???
-  x.into_boxed_slice()
+  let mut x: Vec<Option<Opcode>> = repeat(true).take(MAX_OPCODES).map(|_| None).collect();
+
+  // Join continuation lines first, remembering where each entry started
+  let mut entries: Vec<(usize, String)> = vec!();
+  for (n, line) in src.lines().enumerate() {
+    let line = line.split('#').next().unwrap();
+    if line.trim().len() == 0 {
+      continue;
+    }
+    match entries.last_mut() {
+      Some(&mut (_, ref mut entry)) if line.starts_with(char::is_whitespace) => {
+        entry.push(' ');
+        entry.push_str(line.trim());
+        continue;
+      },
+      _ => ()
+    }
+    if line.starts_with(char::is_whitespace) {
+      return Err(OpcodeTableError { line: n + 1,
+                                    message: "Continuation line without an opcode".to_string() });
+    }
+    entries.push((n + 1, line.trim().to_string()));
+  }
+
+  let nwtypes = get_nwtypes();
+  for (line, entry) in entries {
+    let op = try!(parse_entry(&entry, &nwtypes)
+                  .map_err(|m| OpcodeTableError { line: line, message: m }));
+    let slot = &mut x[op.code as usize];
+    if slot.is_some() {
+      return Err(OpcodeTableError { line: line,
+                                    message: format!("Duplicate entry for {}", op.code) });
+    }
+    *slot = Some(op);
+  }
+
+  Ok(x.into_boxed_slice())
 }
 
-// TODO options for engine types for NWN/DA/DA2 (how?)
+/// The built-in opcode table, covering every engine at once; see `Engine::opcodes` for one
+/// game's.
+pub fn get_opcodes() -> Box<[Option<Opcode>]> {
+  parse_opcodes(BUILTIN_OPCODES).expect("built-in opcode table is invalid")
+}
+
+/// Every type the built-in opcode table knows about, with engine structures given NWN's names
+/// where it has them; see `Engine::nwtypes` for one game's.
 pub fn get_nwtypes() -> Box<[Option<NWType>]> {
 
   use self::NWTypeE::*;
@@ -314,6 +428,7 @@ pub fn get_nwtypes() -> Box<[Option<NWType>]> {
   // Unknown types
   x[NULLQ as usize] = Some(NWType{ code: NULLQ, abbr: None, desc: "Null?" });
   x[COPYQ as usize] = Some(NWType{ code: COPYQ, abbr: None, desc: "Copy?" });
+  x[StateAllQ as usize] = Some(NWType{ code: StateAllQ, abbr: None, desc: "State all?" });
 
   // unary types
   x[I as usize] = Some(NWType{ code: I, abbr: Some("I"), desc: "Integer" });
@@ -326,6 +441,12 @@ pub fn get_nwtypes() -> Box<[Option<NWType>]> {
   x[Event as usize] = Some(NWType{ code: Event, abbr: None, desc: "Event" });
   x[Location as usize] = Some(NWType{ code: Location, abbr: None, desc: "Location" });
   x[Talent as usize] = Some(NWType{ code: Talent, abbr: None, desc: "Talent" });
+  x[Unknownx14 as usize] = Some(NWType{ code: Unknownx14, abbr: None, desc: "???" });
+  x[Unknownx15 as usize] = Some(NWType{ code: Unknownx15, abbr: None, desc: "???" });
+  x[Unknownx16 as usize] = Some(NWType{ code: Unknownx16, abbr: None, desc: "???" });
+  x[Unknownx17 as usize] = Some(NWType{ code: Unknownx17, abbr: None, desc: "???" });
+  x[Unknownx18 as usize] = Some(NWType{ code: Unknownx18, abbr: None, desc: "???" });
+  x[Unknownx19 as usize] = Some(NWType{ code: Unknownx19, abbr: None, desc: "???" });
 
   // binary types
   x[II as usize] = Some(NWType{ code: II, abbr: Some("II"), desc: "Integer, Integer" });
@@ -361,3 +482,31 @@ pub fn get_nwtypes() -> Box<[Option<NWType>]> {
 
   x.into_boxed_slice()
 }
+
+#[cfg(test)]
+mod opcodes_tests {
+  use super::{get_opcodes, parse_opcodes, OpcodeE, Operand};
+
+  #[test]
+  fn builtin_table() {
+    let opcodes = get_opcodes();
+    let op = opcodes[OpcodeE::CONST as usize].as_ref().unwrap();
+    assert_eq!(op.types, Some(vec!(0x03, 0x04, 0x05, 0x06)));
+    match op.args.as_ref().unwrap()[&0x05][..] {
+      [Operand::Size(2), Operand::String] => (),
+      ref a => panic!("unexpected CONST operands {:?}", a)
+    }
+    assert!(opcodes[OpcodeE::RETN as usize].as_ref().unwrap().args.is_none());
+  }
+
+  #[test]
+  fn rejects_bad_tables() {
+    let e = parse_opcodes("JMP 0x1D 0x00 offset:4\nRETN 0x20 0x00\nJMP 0x1D 0x00 offset:4\n");
+    assert_eq!(e.unwrap_err().line, 3);
+    assert!(parse_opcodes("JMP 0x1E 0x00 offset:4\n").is_err());
+    assert!(parse_opcodes("CONST 0x04 0x03 float:4\n").is_err());
+    assert!(parse_opcodes("CONST 0x04 0x05 string\n").is_err());
+    assert!(parse_opcodes("NEG 0x19 0x03 | 0x03\n").is_err());
+    assert!(parse_opcodes("NEG 0x19 0x07\n").is_err());
+  }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use io_utils::read_as_string;
+
+// TODO function-like macros, if any engine's definitions turn out to need them
+
+pub type Defines = HashMap<String, String>;
+
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// A problem found while preprocessing a definitions file.
+#[derive(Debug)]
+pub struct PreprocessError {
+  pub file: String,
+  pub line: usize,
+  pub message: String
+}
+
+impl fmt::Display for PreprocessError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}: {}", self.file, self.line, self.message)
+  }
+}
+
+impl Error for PreprocessError {
+  fn description(&self) -> &str {
+    "preprocessing failed"
+  }
+}
+
+// One level of #if nesting
+struct Cond {
+  parent_active: bool,
+  active: bool, // the current branch is being emitted
+  taken: bool, // some branch has already been emitted
+  seen_else: bool
+}
+
+struct Preprocessor<'a> {
+  defines: &'a mut Defines,
+  includes: Vec<PathBuf>
+}
+
+fn is_ident_start(c: char) -> bool {
+  c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident(c: char) -> bool {
+  c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Split a directive line into its name and the rest, e.g. "# define X 1" -> ("define", "X 1")
+fn split_directive(line: &str) -> (&str, &str) {
+  let body = line.trim_left()[1..].trim_left();
+  let end = body.find(|c: char| !is_ident(c)).unwrap_or(body.len());
+  (&body[..end], body[end..].trim())
+}
+
+// Drop a trailing // comment from a directive, leaving anything inside quotes alone
+fn strip_comment(text: &str) -> &str {
+  let mut quoted = false;
+  let mut prev = ' ';
+  for (n, c) in text.char_indices() {
+    match c {
+      '"' if prev != '\\' => quoted = !quoted,
+      '/' if !quoted && prev == '/' => return text[..n-1].trim_right(),
+      _ => ()
+    }
+    prev = c;
+  }
+  text
+}
+
+impl<'a> Preprocessor<'a> {
+  // Replace defined names in text, skipping strings and comments. Names being expanded are
+  // left alone so self-referencing macros terminate.
+  fn expand(&self, text: &str, in_comment: &mut bool, expanding: &mut Vec<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let chars: Vec<char> = text.chars().collect();
+    let mut n = 0;
+    while n < chars.len() {
+      let c = chars[n];
+      if *in_comment {
+        out.push(c);
+        if c == '*' && chars.get(n + 1) == Some(&'/') {
+          out.push('/');
+          n += 1;
+          *in_comment = false;
+        }
+        n += 1;
+      } else if c == '/' && chars.get(n + 1) == Some(&'/') {
+        out.extend(chars[n..].iter());
+        break;
+      } else if c == '/' && chars.get(n + 1) == Some(&'*') {
+        out.push_str("/*");
+        *in_comment = true;
+        n += 2;
+      } else if c == '"' {
+        let start = n;
+        n += 1;
+        while n < chars.len() && chars[n] != '"' {
+          n += if chars[n] == '\\' { 2 } else { 1 };
+        }
+        n = ::std::cmp::min(n + 1, chars.len());
+        out.extend(chars[start..n].iter());
+      } else if is_ident_start(c) {
+        let start = n;
+        while n < chars.len() && is_ident(chars[n]) {
+          n += 1;
+        }
+        let name: String = chars[start..n].iter().collect();
+        match self.defines.get(&name) {
+          Some(value) if !expanding.contains(&name) => {
+            expanding.push(name.clone());
+            let mut nested = false;
+            out.push_str(&self.expand(value, &mut nested, expanding));
+            expanding.pop();
+          },
+          _ => out.push_str(&name)
+        }
+      } else {
+        out.push(c);
+        n += 1;
+      }
+    }
+    out
+  }
+
+  // Evaluate the condition of an #if or #elif
+  fn condition(&self, text: &str) -> Result<bool, String> {
+    // defined(X) and defined X have to be resolved before macro expansion
+    let mut resolved = String::new();
+    let mut rest = text;
+    while let Some(n) = rest.find("defined") {
+      let boundary = n == 0 || !is_ident(rest[..n].chars().last().unwrap());
+      let after = &rest[n + 7..];
+      if !boundary || after.starts_with(|c: char| is_ident(c)) {
+        resolved.push_str(&rest[..n + 7]);
+        rest = after;
+        continue;
+      }
+      let arg = after.trim_left();
+      let (name, tail) = if arg.starts_with('(') {
+        match arg.find(')') {
+          Some(close) => (arg[1..close].trim(), &arg[close + 1..]),
+          None => return Err("Unclosed defined(".to_string())
+        }
+      } else {
+        let end = arg.find(|c: char| !is_ident(c)).unwrap_or(arg.len());
+        (&arg[..end], &arg[end..])
+      };
+      if name.len() == 0 {
+        return Err("Expected a name after defined".to_string());
+      }
+      resolved.push_str(&rest[..n]);
+      resolved.push_str(if self.defines.contains_key(name) { " 1 " } else { " 0 " });
+      rest = tail;
+    }
+    resolved.push_str(rest);
+
+    let expanded = self.expand(&resolved, &mut false, &mut vec!());
+    let tokens = try!(tokenize(&expanded));
+    let mut parser = CondParser { tokens: tokens, pos: 0 };
+    let value = try!(parser.or());
+    if parser.pos != parser.tokens.len() {
+      return Err(format!("Unexpected {} in condition", parser.tokens[parser.pos]));
+    }
+    Ok(value != 0)
+  }
+
+  fn include(&mut self, path: PathBuf, out: &mut String) -> Result<(), String> {
+    if self.includes.len() >= MAX_INCLUDE_DEPTH {
+      return Err(format!("Includes nested more than {} deep", MAX_INCLUDE_DEPTH));
+    }
+    let key = path.canonicalize().unwrap_or(path.clone());
+    if self.includes.contains(&key) {
+      return Err(format!("{} includes itself", path.display()));
+    }
+    let name = path.to_string_lossy().into_owned();
+    let src = try!(read_as_string(&name).map_err(|e| format!("{}: {}", name, e)));
+    self.includes.push(key);
+    let result = self.run(&src, &name, out);
+    self.includes.pop();
+    result.map_err(|e| e.to_string())
+  }
+
+  fn run(&mut self, src: &str, file: &str, out: &mut String) -> Result<(), PreprocessError> {
+    let mut conds: Vec<Cond> = vec!();
+    let mut in_comment = false;
+    let mut lines = src.lines().enumerate();
+    let dir = Path::new(file).parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::new());
+
+    while let Some((n, first)) = lines.next() {
+      let line_no = n + 1;
+      let err = |m: String| PreprocessError { file: file.to_string(), line: line_no, message: m };
+      let active = conds.last().map_or(true, |c| c.active);
+
+      if in_comment || !first.trim_left().starts_with('#') {
+        if active {
+          out.push_str(&self.expand(first, &mut in_comment, &mut vec!()));
+        }
+        out.push('\n');
+        continue;
+      }
+
+      // Directives continue onto the next line after a trailing backslash
+      let mut line = first.to_string();
+      while line.ends_with('\\') {
+        line.pop();
+        out.push('\n');
+        match lines.next() {
+          Some((_, next)) => line.push_str(next),
+          None => break
+        }
+      }
+      out.push('\n');
+
+      let (directive, rest) = split_directive(&line);
+      let rest = strip_comment(rest);
+      match directive {
+        "if" | "ifdef" | "ifndef" => {
+          let value = if !active {
+            false
+          } else if directive == "if" {
+            try!(self.condition(rest).map_err(&err))
+          } else {
+            let defined = self.defines.contains_key(rest);
+            if directive == "ifdef" { defined } else { !defined }
+          };
+          conds.push(Cond { parent_active: active, active: value, taken: value, seen_else: false });
+        },
+        "elif" | "else" => {
+          let cond = match conds.pop() {
+            Some(c) => c,
+            None => return Err(err(format!("#{} without #if", directive)))
+          };
+          if cond.seen_else {
+            return Err(err(format!("#{} after #else", directive)));
+          }
+          let value = if !cond.parent_active || cond.taken {
+            false
+          } else if directive == "elif" {
+            try!(self.condition(rest).map_err(&err))
+          } else {
+            true
+          };
+          conds.push(Cond { parent_active: cond.parent_active, active: value,
+                            taken: cond.taken || value, seen_else: directive == "else" });
+        },
+        "endif" => {
+          if conds.pop().is_none() {
+            return Err(err("#endif without #if".to_string()));
+          }
+        },
+        _ if !active => (),
+        "define" => {
+          let end = rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len());
+          let (name, value) = (&rest[..end], &rest[end..]);
+          if name.len() == 0 {
+            return Err(err("Expected a name after #define".to_string()));
+          }
+          if value.starts_with('(') {
+            return Err(err(format!("Function-like macro {} is not supported", name)));
+          }
+          self.defines.insert(name.to_string(), value.trim().to_string());
+        },
+        "undef" => { self.defines.remove(rest); },
+        "include" => {
+          let quoted = rest.len() >= 2 && ((rest.starts_with('"') && rest.ends_with('"')) ||
+                                           (rest.starts_with('<') && rest.ends_with('>')));
+          if !quoted {
+            return Err(err(format!("Expected a quoted file name after #include, got {}", rest)));
+          }
+          try!(self.include(dir.join(&rest[1..rest.len()-1]), out).map_err(&err));
+        },
+        "error" => return Err(err(format!("#error {}", rest))),
+        "pragma" => (),
+        _ => return Err(err(format!("Unknown directive #{}", directive)))
+      }
+    }
+
+    if conds.len() > 0 {
+      let line = src.lines().count();
+      return Err(PreprocessError { file: file.to_string(), line: line,
+                                   message: "Unterminated #if".to_string() });
+    }
+    Ok(())
+  }
+}
+
+/// Run the C preprocessor directives in a definitions file: `#define` and `#undef`,
+/// `#include` (relative to the including file), and `#if`, `#ifdef`, `#ifndef`, `#elif`,
+/// `#else` and `#endif`. `path` is the file `src` was read from. Directives and skipped
+/// sections become blank lines so that line numbers outside of includes are unchanged.
+pub fn preprocess(src: &str, path: &str, defines: &mut Defines) -> Result<String, PreprocessError> {
+  let mut out = String::with_capacity(src.len());
+  let mut pp = Preprocessor { defines: defines, includes: vec!() };
+  pp.includes.push(Path::new(path).canonicalize().unwrap_or(PathBuf::from(path)));
+  try!(pp.run(src, path, &mut out));
+  Ok(out)
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+  let chars: Vec<char> = text.chars().collect();
+  let mut tokens = vec!();
+  let mut n = 0;
+  while n < chars.len() {
+    let c = chars[n];
+    if c.is_whitespace() {
+      n += 1;
+    } else if is_ident(c) {
+      let start = n;
+      while n < chars.len() && is_ident(chars[n]) {
+        n += 1;
+      }
+      tokens.push(chars[start..n].iter().collect());
+    } else {
+      let pair: String = chars[n..::std::cmp::min(n + 2, chars.len())].iter().collect();
+      match pair.as_ref() {
+        "&&" | "||" | "==" | "!=" | "<=" | ">=" => { tokens.push(pair); n += 2; },
+        _ if "!<>+-*/%()".contains(c) => { tokens.push(c.to_string()); n += 1; },
+        _ => return Err(format!("Unexpected {} in condition", c))
+      }
+    }
+  }
+  Ok(tokens)
+}
+
+// Recursive descent over C's integer operators, lowest precedence first
+struct CondParser {
+  tokens: Vec<String>,
+  pos: usize
+}
+
+impl CondParser {
+  fn eat(&mut self, ops: &[&str]) -> Option<String> {
+    match self.tokens.get(self.pos) {
+      Some(t) if ops.contains(&t.as_ref()) => { self.pos += 1; Some(t.clone()) },
+      _ => None
+    }
+  }
+
+  fn or(&mut self) -> Result<i64, String> {
+    let mut lhs = try!(self.and());
+    while self.eat(&["||"]).is_some() {
+      let rhs = try!(self.and());
+      lhs = (lhs != 0 || rhs != 0) as i64;
+    }
+    Ok(lhs)
+  }
+
+  fn and(&mut self) -> Result<i64, String> {
+    let mut lhs = try!(self.comparison());
+    while self.eat(&["&&"]).is_some() {
+      let rhs = try!(self.comparison());
+      lhs = (lhs != 0 && rhs != 0) as i64;
+    }
+    Ok(lhs)
+  }
+
+  fn comparison(&mut self) -> Result<i64, String> {
+    let mut lhs = try!(self.sum());
+    while let Some(op) = self.eat(&["==", "!=", "<", ">", "<=", ">="]) {
+      let rhs = try!(self.sum());
+      lhs = match op.as_ref() {
+        "==" => lhs == rhs,
+        "!=" => lhs != rhs,
+        "<" => lhs < rhs,
+        ">" => lhs > rhs,
+        "<=" => lhs <= rhs,
+        _ => lhs >= rhs
+      } as i64;
+    }
+    Ok(lhs)
+  }
+
+  fn sum(&mut self) -> Result<i64, String> {
+    let mut lhs = try!(self.product());
+    while let Some(op) = self.eat(&["+", "-"]) {
+      let rhs = try!(self.product());
+      lhs = if op == "+" { lhs.wrapping_add(rhs) } else { lhs.wrapping_sub(rhs) };
+    }
+    Ok(lhs)
+  }
+
+  fn product(&mut self) -> Result<i64, String> {
+    let mut lhs = try!(self.unary());
+    while let Some(op) = self.eat(&["*", "/", "%"]) {
+      let rhs = try!(self.unary());
+      lhs = match op.as_ref() {
+        "*" => lhs.wrapping_mul(rhs),
+        _ if rhs == 0 => return Err("Division by zero in condition".to_string()),
+        "/" => lhs.wrapping_div(rhs),
+        _ => lhs.wrapping_rem(rhs)
+      };
+    }
+    Ok(lhs)
+  }
+
+  fn unary(&mut self) -> Result<i64, String> {
+    match self.eat(&["!", "-", "+"]) {
+      Some(op) => {
+        let value = try!(self.unary());
+        Ok(match op.as_ref() {
+          "!" => (value == 0) as i64,
+          "-" => value.wrapping_neg(),
+          _ => value
+        })
+      },
+      None => self.primary()
+    }
+  }
+
+  fn primary(&mut self) -> Result<i64, String> {
+    if self.eat(&["("]).is_some() {
+      let value = try!(self.or());
+      if self.eat(&[")"]).is_none() {
+        return Err("Expected ) in condition".to_string());
+      }
+      return Ok(value);
+    }
+    let token = match self.tokens.get(self.pos) {
+      Some(t) => t.clone(),
+      None => return Err("Unexpected end of condition".to_string())
+    };
+    self.pos += 1;
+    if token.starts_with("0x") || token.starts_with("0X") {
+      i64::from_str_radix(&token[2..], 16).map_err(|e| format!("{}: {}", token, e))
+    } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+      token.parse::<i64>().map_err(|e| format!("{}: {}", token, e))
+    } else {
+      Ok(0) // like C, names left after expansion are 0
+    }
+  }
+}
+
+#[cfg(test)]
+mod preprocess_tests {
+  use std::collections::HashMap;
+  use super::preprocess;
+
+  #[test]
+  fn conditionals() {
+    let src = "#define ENGINE 2\n\
+               #if ENGINE >= 2 && !defined(LEGACY)\n\
+               int A = ENGINE;\n\
+               #else\n\
+               int A = 1;\n\
+               #endif\n\
+               string S = \"ENGINE\"; // ENGINE\n";
+    let out = preprocess(src, "defs.ldf", &mut HashMap::new()).unwrap();
+    assert_eq!(out, "\n\nint A = 2;\n\n\n\nstring S = \"ENGINE\"; // ENGINE\n");
+  }
+
+  #[test]
+  fn unterminated_if() {
+    let mut defines = HashMap::new();
+    defines.insert("X".to_string(), "".to_string());
+    let e = preprocess("#ifdef X\nint A = 1;\n", "defs.ldf", &mut defines).unwrap_err();
+    assert_eq!(e.line, 2);
+  }
+
+  #[test]
+  fn overflow() {
+    let src = "#if (-9223372036854775807 - 1) / -1 == -(-9223372036854775807 - 1)\n\
+               int A;\n#endif\n";
+    assert_eq!(preprocess(src, "defs.ldf", &mut HashMap::new()).unwrap(), "\nint A;\n\n");
+  }
+}
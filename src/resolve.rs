@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+
+use super::{Constant, DefinitionError, Literal, NWScriptType, Routine};
+
+
+// Definitions files use these without always declaring them. The object ones are compiler
+// keywords, so stock nwscript.nss uses them as defaults without ever defining them.
+const BUILTINS: [(&'static str, i64); 4] = [
+  ("TRUE", 1), ("FALSE", 0), ("OBJECT_SELF", 0), ("OBJECT_INVALID", 0x7F000000)
+];
+
+// Arithmetic is done at full width and narrowed to the declared type at the end
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+  Int(i64),
+  Float(f64),
+  Other(Literal) // strings, vectors and arrays, which can only be passed through
+}
+
+impl Value {
+  fn from_literal(l: &Literal) -> Value {
+    match *l {
+      Literal::Int(i) => Value::Int(i as i64),
+      Literal::Object(o) => Value::Int(o as i64),
+      Literal::Float(f) => Value::Float(f as f64),
+      ref l => Value::Other(l.clone())
+    }
+  }
+
+  fn to_literal(&self) -> Literal {
+    match *self {
+      Value::Int(i) => Literal::Int(i as i32),
+      Value::Float(f) => Literal::Float(f as f32),
+      Value::Other(ref l) => l.clone()
+    }
+  }
+
+  // Narrow to the declared type, or explain why it doesn't fit
+  fn typed(self, t: &NWScriptType) -> Result<Literal, String> {
+    match (t.base(), self) {
+      (&NWScriptType::Int, Value::Int(i)) => Ok(Literal::Int(i as i32)),
+      (&NWScriptType::Object, Value::Int(i)) => Ok(Literal::Object(i as u32)),
+      (&NWScriptType::Float, Value::Int(i)) => Ok(Literal::Float(i as f32)),
+      (&NWScriptType::Float, Value::Float(f)) => Ok(Literal::Float(f as f32)),
+      (&NWScriptType::String, Value::Other(l @ Literal::String(_))) |
+      (&NWScriptType::Resource, Value::Other(l @ Literal::ResRef(_))) |
+      (&NWScriptType::Vector, Value::Other(l @ Literal::Vector(..))) |
+      (&NWScriptType::Array(_), Value::Other(l @ Literal::Array(_))) => Ok(l),
+      (&NWScriptType::Resource, Value::Other(Literal::String(s))) => Ok(Literal::ResRef(s)),
+      (&NWScriptType::Int, v) | (&NWScriptType::Object, v) | (&NWScriptType::Float, v) |
+      (&NWScriptType::String, v) | (&NWScriptType::Resource, v) |
+      (&NWScriptType::Vector, v) | (&NWScriptType::Array(_), v) =>
+        Err(format!("Expected {}, found {}", t, v.to_literal())),
+      // engine structures have no literals of their own, so take whatever is given
+      (_, v) => Ok(v.to_literal())
+    }
+  }
+}
+
+struct Resolver<'a> {
+  constants: &'a HashMap<String, Constant>,
+  values: HashMap<String, Value>,
+  stack: Vec<String> // constants being resolved, to catch cycles
+}
+
+impl<'a> Resolver<'a> {
+  fn lookup(&mut self, name: &str, context: &str) -> Result<Value, DefinitionError> {
+    if let Some(v) = self.values.get(name) {
+      return Ok(v.clone());
+    }
+    let c = match self.constants.get(name) {
+      Some(c) => c,
+      None => return match BUILTINS.iter().find(|b| b.0 == name) {
+        Some(b) => Ok(Value::Int(b.1)),
+        None => Err(DefinitionError::UndefinedConstant(name.to_string(), context.to_string()))
+      }
+    };
+    if let Some(n) = self.stack.iter().position(|s| s == name) {
+      let mut cycle = self.stack[n..].to_vec();
+      cycle.push(name.to_string());
+      return Err(DefinitionError::CyclicConstant(cycle));
+    }
+
+    let text = match c.value {
+      Literal::Expression(ref e) => e,
+      ref l => return Ok(Value::from_literal(l))
+    };
+
+    self.stack.push(name.to_string());
+    let value = self.evaluate(text, &format!("constant {}", name));
+    self.stack.pop();
+    let value = try!(value);
+    self.values.insert(name.to_string(), value.clone());
+    Ok(value)
+  }
+
+  fn evaluate(&mut self, text: &str, context: &str) -> Result<Value, DefinitionError> {
+    let t = text.trim();
+    let bad = |m: String| DefinitionError::BadExpression(context.to_string(), m);
+    let tokens = try!(tokenize(t).map_err(&bad));
+    let mut parser = Parser { tokens: tokens, pos: 0, context: context.to_string() };
+    let value = try!(parser.bit_or(self));
+    if parser.pos < parser.tokens.len() {
+      return Err(bad(format!("Unexpected {}", parser.tokens[parser.pos])));
+    }
+    Ok(value)
+  }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+  let chars: Vec<char> = text.chars().collect();
+  let mut tokens = vec!();
+  let mut n = 0;
+  while n < chars.len() {
+    let c = chars[n];
+    if c.is_whitespace() {
+      n += 1;
+    } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+      let start = n;
+      while n < chars.len() && (chars[n].is_ascii_alphanumeric() || chars[n] == '_' ||
+                                chars[n] == '.') {
+        n += 1;
+      }
+      tokens.push(chars[start..n].iter().collect());
+    } else if (c == '<' || c == '>') && chars.get(n + 1) == Some(&c) {
+      tokens.push(chars[n..n + 2].iter().collect());
+      n += 2;
+    } else if "-~+*/%&|^()".contains(c) {
+      tokens.push(c.to_string());
+      n += 1;
+    } else {
+      return Err(format!("Unexpected {}", c));
+    }
+  }
+  Ok(tokens)
+}
+
+// Integer-only binary operators
+fn int_op(op: &str, l: i64, r: i64) -> Result<i64, String> {
+  Ok(match op {
+    "|" => l | r,
+    "^" => l ^ r,
+    "&" => l & r,
+    "<<" => l.wrapping_shl(r as u32),
+    ">>" => l.wrapping_shr(r as u32),
+    "%" if r == 0 => return Err("Division by zero".to_string()),
+    _ => l.wrapping_rem(r)
+  })
+}
+
+// Recursive descent with C precedence, lowest first
+struct Parser {
+  tokens: Vec<String>,
+  pos: usize,
+  context: String
+}
+
+impl Parser {
+  fn eat(&mut self, ops: &[&str]) -> Option<String> {
+    match self.tokens.get(self.pos) {
+      Some(t) if ops.contains(&t.as_ref()) => { self.pos += 1; Some(t.clone()) },
+      _ => None
+    }
+  }
+
+  fn err(&self, m: String) -> DefinitionError {
+    DefinitionError::BadExpression(self.context.clone(), m)
+  }
+
+  // Apply op to two values, promoting ints to floats like NWScript does
+  fn apply(&self, op: &str, l: Value, r: Value) -> Result<Value, DefinitionError> {
+    match (l, r) {
+      (Value::Int(l), Value::Int(r)) => match op {
+        "+" => Ok(Value::Int(l.wrapping_add(r))),
+        "-" => Ok(Value::Int(l.wrapping_sub(r))),
+        "*" => Ok(Value::Int(l.wrapping_mul(r))),
+        "/" if r == 0 => Err(self.err("Division by zero".to_string())),
+        "/" => Ok(Value::Int(l.wrapping_div(r))),
+        _ => int_op(op, l, r).map(Value::Int).map_err(|m| self.err(m))
+      },
+      (Value::Float(l), Value::Int(r)) => self.apply(op, Value::Float(l), Value::Float(r as f64)),
+      (Value::Int(l), Value::Float(r)) => self.apply(op, Value::Float(l as f64), Value::Float(r)),
+      (Value::Float(l), Value::Float(r)) => match op {
+        "+" => Ok(Value::Float(l + r)),
+        "-" => Ok(Value::Float(l - r)),
+        "*" => Ok(Value::Float(l * r)),
+        "/" => Ok(Value::Float(l / r)),
+        _ => Err(self.err(format!("{} needs integer operands", op)))
+      },
+      (Value::Other(ref l), _) | (_, Value::Other(ref l)) =>
+        Err(self.err(format!("Can't use {} in arithmetic", l)))
+    }
+  }
+
+  fn binary(&mut self, r: &mut Resolver, ops: &[&str],
+            next: fn(&mut Parser, &mut Resolver) -> Result<Value, DefinitionError>)
+            -> Result<Value, DefinitionError> {
+    let mut lhs = try!(next(self, r));
+    while let Some(op) = self.eat(ops) {
+      let rhs = try!(next(self, r));
+      lhs = try!(self.apply(&op, lhs, rhs));
+    }
+    Ok(lhs)
+  }
+
+  fn bit_or(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["|"], Parser::bit_xor)
+  }
+
+  fn bit_xor(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["^"], Parser::bit_and)
+  }
+
+  fn bit_and(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["&"], Parser::shift)
+  }
+
+  fn shift(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["<<", ">>"], Parser::sum)
+  }
+
+  fn sum(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["+", "-"], Parser::product)
+  }
+
+  fn product(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    self.binary(r, &["*", "/", "%"], Parser::unary)
+  }
+
+  fn unary(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    match self.eat(&["-", "~"]) {
+      Some(op) => match (op.as_ref(), try!(self.unary(r))) {
+        ("-", Value::Int(i)) => Ok(Value::Int(i.wrapping_neg())),
+        ("-", Value::Float(f)) => Ok(Value::Float(-f)),
+        ("~", Value::Int(i)) => Ok(Value::Int(!i)),
+        (_, v) => Err(self.err(format!("Can't apply {} to {}", op, v.to_literal())))
+      },
+      None => self.primary(r)
+    }
+  }
+
+  fn primary(&mut self, r: &mut Resolver) -> Result<Value, DefinitionError> {
+    if self.eat(&["("]).is_some() {
+      let value = try!(self.bit_or(r));
+      if self.eat(&[")"]).is_none() {
+        return Err(self.err("Expected )".to_string()));
+      }
+      return Ok(value);
+    }
+    let token = match self.tokens.get(self.pos) {
+      Some(t) => t.clone(),
+      None => return Err(self.err("Unexpected end of expression".to_string()))
+    };
+    self.pos += 1;
+
+    if token.starts_with("0x") || token.starts_with("0X") {
+      i64::from_str_radix(&token[2..], 16).map(Value::Int)
+        .map_err(|e| self.err(format!("{}: {}", token, e)))
+    } else if token.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+      if token.contains('.') {
+        token.trim_right_matches('f').parse::<f64>().map(Value::Float)
+          .map_err(|e| self.err(format!("{}: {}", token, e)))
+      } else {
+        token.parse::<i64>().map(Value::Int).map_err(|e| self.err(format!("{}: {}", token, e)))
+      }
+    } else {
+      r.lookup(&token, &self.context)
+    }
+  }
+}
+
+/// Evaluate constant references and arithmetic left as `Literal::Expression` in constant
+/// values and routine argument defaults, e.g. `int nType = DAMAGE_TYPE_FIRE` or
+/// `OBJECT_TYPE_ALL - 1`, and narrow the result to the declared type. `TRUE` and `FALSE` are
+/// 1 and 0 unless declared.
+///
+/// Fails on references to undeclared constants, cycles, arithmetic on strings, and results
+/// that don't fit the declared type.
+pub fn resolve_constants(constants: &mut HashMap<String, Constant>,
+                         routines: &mut HashMap<u16, Routine>) -> Result<(), DefinitionError> {
+  let mut resolved = vec!();
+  let mut defaults = vec!();
+  {
+    let mut resolver = Resolver { constants: constants, values: HashMap::new(), stack: vec!() };
+    let mut names: Vec<&String> = constants.keys().collect();
+    names.sort(); // report the same error every run
+    for name in names {
+      let c = &constants[name];
+      if let Literal::Expression(_) = c.value {
+        let context = format!("constant {}", name);
+        let value = try!(resolver.lookup(name, &context));
+        let value = try!(value.typed(&c.nwtype)
+                         .map_err(|m| DefinitionError::BadExpression(context, m)));
+        resolved.push((name.clone(), value));
+      }
+    }
+
+    let mut codes: Vec<&u16> = routines.keys().collect();
+    codes.sort();
+    for code in codes {
+      let rtn = &routines[code];
+      for (n, arg) in rtn.args.iter().enumerate() {
+        if let Some(Literal::Expression(ref e)) = arg.default_value {
+          let context = format!("argument {} of routine {}", arg.name, rtn.name);
+          let value = try!(resolver.evaluate(e, &context));
+          let value = try!(value.typed(&arg.nwtype)
+                           .map_err(|m| DefinitionError::BadExpression(context, m)));
+          defaults.push((*code, n, value));
+        }
+      }
+    }
+  }
+
+  for (name, value) in resolved {
+    constants.get_mut(&name).unwrap().value = value;
+  }
+  for (code, n, value) in defaults {
+    routines.get_mut(&code).unwrap().args[n].default_value = Some(value);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod resolve_tests {
+  use {build_tables, parse_definitions, parse_nwn_definitions, DefinitionError, Literal};
+  use super::resolve_constants;
+
+  #[test]
+  fn expressions() {
+    let src = "int OBJECT_TYPE_ALL = 0x7FFF;\n\
+               int OBJECT_TYPE_SOME = OBJECT_TYPE_ALL - 1;\n\
+               float HALF = (OBJECT_TYPE_SOME + 2) / 2.0f;\n\
+               void Damage(int nType = OBJECT_TYPE_SOME & ~1, int bLive = TRUE) = 3;\n";
+    let (mut constants, mut routines) = build_tables(parse_definitions(src).unwrap()).unwrap();
+    resolve_constants(&mut constants, &mut routines).unwrap();
+    assert_eq!(constants["OBJECT_TYPE_ALL"].value, Literal::Int(0x7FFF));
+    assert_eq!(constants["OBJECT_TYPE_SOME"].value, Literal::Int(32766));
+    assert_eq!(constants["HALF"].value, Literal::Float(16384.0));
+    assert_eq!(routines[&3].args[0].default_value, Some(Literal::Int(32766)));
+    assert_eq!(routines[&3].args[1].default_value, Some(Literal::Int(1)));
+  }
+
+  #[test]
+  fn overflow() {
+    let src = "int BIG = (-9223372036854775807 - 1) / -1;\n\
+               int REM = (-9223372036854775807 - 1) % -1;\n";
+    let (mut constants, mut routines) = build_tables(parse_definitions(src).unwrap()).unwrap();
+    resolve_constants(&mut constants, &mut routines).unwrap();
+    assert_eq!(constants["BIG"].value, Literal::Int(0));
+    assert_eq!(constants["REM"].value, Literal::Int(0));
+  }
+
+  #[test]
+  fn object_keywords() {
+    let src = "object GetNearestObject(int nNth = 1, object oTarget = OBJECT_SELF);\n\
+               int GetIsObjectValid(object oObject = OBJECT_INVALID);\n";
+    let (mut constants, mut routines) = build_tables(parse_nwn_definitions(src).unwrap()).unwrap();
+    resolve_constants(&mut constants, &mut routines).unwrap();
+    assert_eq!(routines[&0].args[1].default_value, Some(Literal::Object(0)));
+    assert_eq!(routines[&1].args[0].default_value, Some(Literal::Object(0x7F000000)));
+  }
+
+  #[test]
+  fn bad_references() {
+    let src = "int A = B + 1;\nint B = C;\nint C = A;\nint D = E;\n";
+    let (mut constants, mut routines) = build_tables(parse_definitions(src).unwrap()).unwrap();
+    match resolve_constants(&mut constants, &mut routines) {
+      Err(DefinitionError::CyclicConstant(c)) => assert_eq!(c, vec!("A", "B", "C", "A")),
+      r => panic!("expected a cycle, got {:?}", r)
+    }
+    constants.remove("A");
+    match resolve_constants(&mut constants, &mut routines) {
+      Err(DefinitionError::UndefinedConstant(name, _)) => assert_eq!(name, "A"),
+      r => panic!("expected an undefined constant, got {:?}", r)
+    }
+  }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use super::Routine;
+use cfg::{state_entry, subroutine_name, Cfg};
+use disassemble::jump_target;
+use opcodes::{OpPayload, OpcodeE};
+
+
+/// Something wrong with how a script uses the stack, found by `check_stack`.
+#[derive(Debug, PartialEq)]
+pub struct StackProblem {
+  pub offset: usize,
+  pub message: String
+}
+
+impl fmt::Display for StackProblem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:08X}: {}", self.offset, self.message)
+  }
+}
+
+/// What a function does to the stack, in bytes relative to the depth it was entered with.
+#[derive(Debug)]
+pub struct FunctionStack {
+  pub entry: usize,
+  pub returns: Option<i32>, // depth at RETN, None if it never returns
+  pub lowest: i32 // deepest byte it reads, writes or pops
+}
+
+#[derive(Debug)]
+pub struct StackReport {
+  pub depths: BTreeMap<usize, i32>, // before each instruction, relative to its function's entry
+  pub functions: BTreeMap<usize, FunctionStack>,
+  pub problems: Vec<StackProblem>
+}
+
+// An instruction's change to the stack depth, and the deepest point it touches on the way. Kept
+// in i64 so that hostile operands can't overflow it.
+struct Effect {
+  reach: i64,
+  delta: i64
+}
+
+// Depths are reported as i32, and must stay negatable
+fn in_range(n: i64) -> bool {
+  n.abs() <= i32::max_value() as i64
+}
+
+fn pops(bytes: i64, pushes: i64) -> Effect {
+  Effect { reach: -bytes, delta: pushes - bytes }
+}
+
+fn effect(op: &OpPayload, routines: &HashMap<u16, Routine>) -> Result<Effect, String> {
+  let args = op.numbers();
+  let arg = |n: usize| args.get(n).cloned().unwrap_or(0) as i64;
+  let binary = match op._type {
+    Some(0x24) => pops(arg(0) * 2, 4),
+    Some(0x3A) => pops(24, 12),
+    Some(0x3B) | Some(0x3C) => pops(16, 12),
+    _ => pops(8, 4)
+  };
+
+  Ok(match op.op.code {
+    OpcodeE::CPDOWNSP | OpcodeE::DECISP | OpcodeE::INCISP => Effect { reach: arg(0), delta: 0 },
+    OpcodeE::CPTOPSP => Effect { reach: arg(0), delta: arg(1) },
+    OpcodeE::CPTOPBP => Effect { reach: 0, delta: arg(1) },
+    OpcodeE::RSADD | OpcodeE::CONST | OpcodeE::SAVEBP => pops(0, 4),
+    OpcodeE::ACTION => {
+      let routine = match routines.get(&(arg(0) as u16)) {
+        Some(r) => r,
+        None => return Err(format!("unknown routine {}, its stack use can't be checked", arg(0)))
+      };
+      let count = arg(1) as usize;
+      if count > routine.args.len() {
+        return Err(format!("{} takes {} arguments but is passed {}", routine.name,
+                           routine.args.len(), count));
+      }
+      let size: usize = routine.args[..count].iter().map(|a| a.nwtype.stack_size()).sum();
+      pops(size as i64, routine.return_type.stack_size() as i64)
+    },
+    OpcodeE::LOGANDII | OpcodeE::LOGORII | OpcodeE::INCORII | OpcodeE::EXCORII |
+    OpcodeE::BOOLANDII | OpcodeE::EQUAL | OpcodeE::NEQUAL | OpcodeE::GEQ | OpcodeE::GT |
+    OpcodeE::LT | OpcodeE::LEQ | OpcodeE::SHLEFTII | OpcodeE::SHRIGHTII | OpcodeE::USHRIGHTII |
+    OpcodeE::ADD | OpcodeE::SUB | OpcodeE::MUL | OpcodeE::DIV | OpcodeE::MODII => binary,
+    OpcodeE::NEG | OpcodeE::COMPI | OpcodeE::NOTI => pops(4, 4),
+    OpcodeE::MOVSP => Effect { reach: arg(0), delta: arg(0) },
+    OpcodeE::JZ | OpcodeE::JNZ | OpcodeE::RESTOREBP => pops(4, 0),
+    OpcodeE::DESTRUCT => pops(arg(0), arg(2)),
+    OpcodeE::JMP | OpcodeE::RETN | OpcodeE::CPDOWNBP | OpcodeE::DECIBP | OpcodeE::INCIBP |
+    OpcodeE::STORE_STATE | OpcodeE::STORE_STATEALL | OpcodeE::NOP | OpcodeE::T => pops(0, 0),
+    code => return Err(format!("{:?} has an unknown stack effect", code))
+  })
+}
+
+struct Checker<'a, 'b: 'a> {
+  ops: &'a [OpPayload<'b>],
+  cfg: &'a Cfg,
+  routines: &'a HashMap<u16, Routine>,
+  started: HashSet<usize>,
+  report: StackReport
+}
+
+impl<'a, 'b> Checker<'a, 'b> {
+  fn problem(&mut self, offset: usize, message: String) {
+    self.report.problems.push(StackProblem { offset: offset, message: message });
+  }
+
+  // Walk a function's blocks from its entry. The floor is the lowest depth it may touch, if
+  // that is known; it isn't for JSR targets, which get checked where they are called.
+  fn check_function(&mut self, entry: usize, floor: Option<i64>) {
+    if !self.started.insert(entry) {
+      return;
+    }
+    let mut returns: Option<(usize, i64)> = None;
+    let mut lowest: i64 = 0;
+    let mut starts = BTreeMap::new();
+    starts.insert(entry, 0);
+    let mut todo = vec!(entry);
+
+    'blocks: while let Some(start) = todo.pop() {
+      let block = match self.cfg.blocks.get(&start) {
+        Some(b) => b,
+        None => continue
+      };
+      let mut depth = starts[&start];
+      for n in block.ops.iter() {
+        let op = &self.ops[*n];
+        self.report.depths.insert(op.offset, depth as i32);
+
+        let e = match op.op.code {
+          OpcodeE::RETN => {
+            match returns {
+              None => returns = Some((op.offset, depth)),
+              Some((_, d)) if d != depth =>
+                self.problem(op.offset, format!("returns with {} bytes on the stack, but {} on \
+                                                 another path", depth, d)),
+              _ => ()
+            }
+            continue 'blocks;
+          },
+          OpcodeE::JSR => {
+            let target = jump_target(op).unwrap_or(0);
+            self.check_function(target, None);
+            match self.report.functions.get(&target).map(|f| (f.returns, f.lowest)) {
+              Some((Some(returns), lowest)) =>
+                Effect { reach: lowest as i64, delta: returns as i64 },
+              Some((None, _)) => {
+                self.problem(op.offset, format!("{} never returns", subroutine_name(target)));
+                continue 'blocks;
+              },
+              None => {
+                self.problem(op.offset, format!("recursive call to {}, the stack isn't checked \
+                                                 past it", subroutine_name(target)));
+                continue 'blocks;
+              }
+            }
+          },
+          _ => match effect(op, self.routines) {
+            Ok(e) => e,
+            Err(message) => {
+              self.problem(op.offset, message);
+              continue 'blocks;
+            }
+          }
+        };
+
+        if !in_range(depth + e.reach) || !in_range(depth + e.delta) {
+          self.problem(op.offset, "moves the stack pointer out of range".to_string());
+          continue 'blocks;
+        }
+        lowest = ::std::cmp::min(lowest, depth + e.reach);
+        if let Some(floor) = floor {
+          if depth + e.reach < floor {
+            self.problem(op.offset, format!("reaches {} bytes below the bottom of the stack",
+                                            floor - depth - e.reach));
+            continue 'blocks;
+          }
+        }
+        depth += e.delta;
+      }
+
+      for &(_, target) in block.successors.iter() {
+        match starts.get(&target).cloned() {
+          Some(d) if d != depth => {
+            self.problem(target, format!("stack is {} bytes deep on one path here but {} on \
+                                          another", d, depth));
+          },
+          Some(_) => (),
+          None => {
+            starts.insert(target, depth);
+            todo.push(target);
+          }
+        }
+      }
+    }
+
+    // Functions clean up after themselves, leaving at most a result in a slot made by the caller
+    if let Some((offset, depth)) = returns {
+      if floor.is_none() && depth > 0 {
+        self.problem(offset, format!("returns with {} more bytes on the stack than it was \
+                                      called with", depth));
+      }
+    }
+    self.report.functions.insert(entry, FunctionStack { entry: entry,
+                                                        returns: returns.map(|r| r.1 as i32),
+                                                        lowest: lowest as i32 });
+  }
+}
+
+/// Track the stack depth at each instruction over the control-flow graph, following JSRs and
+/// using the routine signatures for ACTION. Finds paths that join with different depths,
+/// instructions that reach below the bottom of the stack and functions that return with
+/// inconsistent or leftover stack.
+pub fn check_stack(ops: &[OpPayload],
+                   cfg: &Cfg,
+                   routines: &HashMap<u16, Routine>) -> StackReport {
+  // Code saved by STORE_STATE starts with a copy of the top of the stack
+  let mut floors: HashMap<usize, i64> = (0..ops.len())
+    .filter_map(|n| state_entry(ops, n).map(|e| (e, -(ops[n].numbers().get(1).cloned()
+                                                     .unwrap_or(0) as i64))))
+    .collect();
+  if let Some(f) = cfg.functions.first() {
+    floors.insert(f.entry, 0);
+  }
+
+  let mut checker = Checker {
+    ops: ops,
+    cfg: cfg,
+    routines: routines,
+    started: HashSet::new(),
+    report: StackReport { depths: BTreeMap::new(), functions: BTreeMap::new(), problems: vec!() }
+  };
+  for f in cfg.functions.iter() {
+    checker.check_function(f.entry, floors.get(&f.entry).cloned());
+  }
+
+  // The entry point leaves StartingConditional's result behind, if it has one
+  let mut report = checker.report;
+  if let Some(f) = cfg.functions.first().and_then(|f| report.functions.get(&f.entry)) {
+    match f.returns {
+      Some(0) | Some(4) | None => (),
+      Some(n) => report.problems.push(StackProblem {
+        offset: f.entry,
+        message: format!("script ends with {} bytes on the stack", n)
+      })
+    }
+  }
+  report.problems.sort_by_key(|p| p.offset);
+  report.problems.dedup();
+  report
+}
+
+#[cfg(test)]
+mod stack_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use cfg::build_cfg;
+  use disassemble::read_ops;
+  use opcodes::get_opcodes;
+  use super::check_stack;
+
+  #[test]
+  fn depths() {
+    // 0D JSR +8, 13 RETN, 15 CONSTI 1, 1B CONSTI 2, 21 ADDII, 23 MOVSP -4, 29 RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x2B\x1E\x00\x00\x00\x00\x08\x20\x00\
+                 \x04\x03\x00\x00\x00\x01\x04\x03\x00\x00\x00\x02\x14\x20\
+                 \x1B\x00\xFF\xFF\xFF\xFC\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let report = check_stack(&ops, &build_cfg(&ops), &HashMap::new());
+    assert_eq!(report.problems, vec!());
+    assert_eq!(report.depths[&0x21], 8);
+    assert_eq!(report.depths[&0x29], 0);
+    assert_eq!(report.functions[&0x15].returns, Some(0));
+  }
+
+  #[test]
+  fn problems() {
+    // 0D CONSTI 1, 13 JZ +8, 19 CONSTI 2, 1F MOVSP -8, 25 RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x27\x04\x03\x00\x00\x00\x01\x1F\x00\x00\x00\x00\x0C\
+                 \x04\x03\x00\x00\x00\x02\x1B\x00\xFF\xFF\xFF\xF8\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let report = check_stack(&ops, &build_cfg(&ops), &HashMap::new());
+    let messages: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+    assert_eq!(messages, vec!(
+      "0000001F: stack is 0 bytes deep on one path here but 4 on another",
+      "0000001F: reaches 8 bytes below the bottom of the stack"
+    ));
+  }
+
+  #[test]
+  fn out_of_range() {
+    // 0D MOVSP i32::MIN, 13 RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x15\x1B\x00\x80\x00\x00\x00\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let report = check_stack(&ops, &build_cfg(&ops), &HashMap::new());
+    let messages: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+    assert_eq!(messages, vec!("0000000D: moves the stack pointer out of range"));
+  }
+}
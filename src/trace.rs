@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+use super::Routine;
+use actions::Registry;
+use disassemble::{format_instruction, DisassemblyError};
+use io_utils::Encoding;
+use opcodes::{Opcode, OpcodeE};
+use vm::{Value, Vm, VmError};
+
+
+/// Settings for `trace`.
+#[derive(Debug, Clone)]
+pub struct TraceOptions {
+  /// Write one JSON object per instruction instead of text.
+  pub json: bool,
+  /// How many values from the top of the stack to show.
+  pub top: usize,
+  /// Give up after this many instructions, in case the script never returns.
+  pub steps: usize,
+  pub encoding: Encoding
+}
+
+impl Default for TraceOptions {
+  fn default() -> TraceOptions {
+    TraceOptions { json: false, top: 4, steps: 1000000, encoding: Encoding::default() }
+  }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+  IOError(io::Error),
+  Disassembly(DisassemblyError),
+  Run(VmError)
+}
+
+impl fmt::Display for TraceError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      TraceError::IOError(ref e) => write!(f, "{}", e),
+      TraceError::Disassembly(ref e) => write!(f, "{}", e),
+      TraceError::Run(ref e) => write!(f, "script failed at {}", e)
+    }
+  }
+}
+
+impl Error for TraceError {
+  fn description(&self) -> &str {
+    "trace failed"
+  }
+}
+
+impl From<io::Error> for TraceError {
+  fn from(e: io::Error) -> TraceError {
+    TraceError::IOError(e)
+  }
+}
+
+// Where control went after a jump, if it was one
+fn branch(code: OpcodeE, next: usize, pc: usize) -> Option<(&'static str, Option<usize>)> {
+  match code {
+    OpcodeE::JZ | OpcodeE::JNZ if pc == next => Some(("not taken", None)),
+    OpcodeE::JZ | OpcodeE::JNZ => Some(("taken", Some(pc))),
+    OpcodeE::JMP | OpcodeE::JSR => Some(("jump", Some(pc))),
+    OpcodeE::RETN => Some(("return", Some(pc))),
+    _ => None
+  }
+}
+
+fn json_string(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c)
+    }
+  }
+  out.push('"');
+  out
+}
+
+fn json_list(items: &[String]) -> String {
+  let items: Vec<String> = items.iter().map(|s| json_string(s)).collect();
+  format!("[{}]", items.join(","))
+}
+
+/// Run a script in `vm` to the end, writing a line for each instruction executed: the
+/// instruction as `disassemble` prints it, the stack pointer, base pointer and top of the
+/// stack afterwards, where any jump went, and the engine routines it called that the registry
+/// doesn't implement.
+pub fn trace<W: Write>(wtr: &mut W,
+                       vm: &mut Vm,
+                       registry: &mut Registry,
+                       opcodes: &[Option<Opcode>],
+                       routines: &HashMap<u16, Routine>,
+                       options: &TraceOptions) -> Result<(), TraceError> {
+  let mut text = HashMap::new();
+  let mut steps = 0;
+  while !vm.halted {
+    if steps == options.steps {
+      return Err(TraceError::Run(VmError { offset: vm.pc,
+                                           message: format!("stopped after {} steps", steps) }));
+    }
+    steps += 1;
+
+    let op = match vm.current() {
+      Some(op) => op,
+      None => {
+        try!(vm.step(registry).map_err(TraceError::Run)); // fails with the reason
+        continue;
+      }
+    };
+    if !text.contains_key(&op.offset) {
+      let s = try!(format_instruction(op, opcodes, routines, registry.engine(),
+                                      options.encoding)
+                   .map_err(TraceError::Disassembly));
+      text.insert(op.offset, s.trim_right().to_string());
+    }
+    let logged = registry.log.len();
+    try!(vm.step(registry).map_err(TraceError::Run));
+
+    let skip = vm.stack.len().saturating_sub(options.top);
+    let top: Vec<String> = vm.stack[skip..].iter().map(Value::to_string).collect();
+    let jump = branch(op.op.code, op.offset + op.bytes_read, vm.pc);
+    let calls = &registry.log[logged..];
+
+    if options.json {
+      try!(write!(wtr, "{{\"offset\":{},\"instruction\":{},\"sp\":{},\"bp\":{},\"stack\":{}",
+                  op.offset, json_string(&text[&op.offset]), vm.sp(), vm.bp, json_list(&top)));
+      match jump {
+        Some((kind, Some(to))) if !vm.halted =>
+          try!(write!(wtr, ",\"branch\":\"{}\",\"target\":{}", kind, to)),
+        Some((kind, _)) => try!(write!(wtr, ",\"branch\":\"{}\"", kind)),
+        None => ()
+      }
+      if calls.len() > 0 {
+        try!(write!(wtr, ",\"calls\":{}", json_list(calls)));
+      }
+      try!(writeln!(wtr, "}}"));
+      continue;
+    }
+
+    let more = if skip > 0 { "..., " } else { "" };
+    try!(write!(wtr, "{:08X}  {:<40} sp={:<4} bp={:<4} [{}{}]", op.offset, text[&op.offset],
+                vm.sp(), vm.bp, more, top.join(", ")));
+    match jump {
+      Some((_, _)) if vm.halted => try!(write!(wtr, "  halt")),
+      Some(("jump", Some(to))) | Some(("return", Some(to))) =>
+        try!(write!(wtr, "  -> {:08X}", to)),
+      Some((kind, Some(to))) => try!(write!(wtr, "  {} -> {:08X}", kind, to)),
+      Some((kind, None)) => try!(write!(wtr, "  {}", kind)),
+      None => ()
+    }
+    try!(writeln!(wtr, ""));
+    for call in calls.iter() {
+      try!(writeln!(wtr, "          ; {}", call));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod trace_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use actions::Registry;
+  use disassemble::read_ops;
+  use engine::Engine;
+  use opcodes::get_opcodes;
+  use vm::Vm;
+  use super::{trace, TraceOptions};
+
+  // 0D CONSTI 0, 13 JZ +8, 19 RETN, 1B CONSTS "a\"", 21 RETN
+  const SCRIPT: &'static [u8] = b"NCS V1.0\x42\x00\x00\x00\x23\x04\x03\x00\x00\x00\x00\
+                                  \x1F\x00\x00\x00\x00\x08\x20\x00\x04\x05\x00\x02a\"\x20\x00";
+
+  fn run(options: &TraceOptions) -> Vec<String> {
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(SCRIPT), &opcodes).unwrap();
+    let routines = HashMap::new();
+    let mut registry = Registry::new(Engine::Generic, 0);
+    let mut vm = Vm::new(&ops, &routines, options.encoding);
+    let mut out = vec!();
+    trace(&mut out, &mut vm, &mut registry, &opcodes, &routines, options).unwrap();
+    String::from_utf8(out).unwrap().lines().map(|l| l.to_string()).collect()
+  }
+
+  #[test]
+  fn text() {
+    let lines = run(&TraceOptions::default());
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("00000013  JZ"));
+    assert!(lines[1].ends_with("sp=0    bp=0    []  taken -> 0000001B"));
+    assert!(lines[3].ends_with("[\"a\\\"\"]  halt"));
+  }
+
+  #[test]
+  fn json() {
+    let lines = run(&TraceOptions { json: true, top: 1, ..Default::default() });
+    assert_eq!(lines[0], "{\"offset\":13,\"instruction\":\"CONSTI        0\",\"sp\":4,\"bp\":0,\
+                          \"stack\":[\"0\"]}");
+    assert!(lines[1].ends_with(",\"branch\":\"taken\",\"target\":27}"));
+    assert!(lines[2].contains("\"stack\":[\"\\\"a\\\\\\\"\\\"\"]"));
+  }
+}
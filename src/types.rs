@@ -0,0 +1,191 @@
+use std::fmt;
+
+
+/// A type as written in a definitions file, e.g. `int`, `object` or `ref string[]`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NWScriptType {
+  Int,
+  Float,
+  String,
+  Void,
+  Any,
+  Action,
+  Command,
+  Effect,
+  Event,
+  ItemProperty,
+  Location,
+  Object,
+  Player,
+  Resource,
+  Talent,
+  Vector,
+  Array(Box<NWScriptType>),
+  Ref(Box<NWScriptType>)
+}
+
+impl NWScriptType {
+  /// The type with any `ref` modifier removed.
+  pub fn base(&self) -> &NWScriptType {
+    match *self {
+      NWScriptType::Ref(ref t) => t.base(),
+      ref t => t
+    }
+  }
+
+  /// Bytes a value of this type takes on the VM stack. Action arguments are saved with
+  /// STORE_STATE instead of being pushed, so they take none.
+  pub fn stack_size(&self) -> usize {
+    match *self {
+      NWScriptType::Void | NWScriptType::Action => 0,
+      NWScriptType::Vector => 12,
+      _ => 4
+    }
+  }
+}
+
+impl fmt::Display for NWScriptType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match *self {
+      NWScriptType::Int => "int",
+      NWScriptType::Float => "float",
+      NWScriptType::String => "string",
+      NWScriptType::Void => "void",
+      NWScriptType::Any => "any",
+      NWScriptType::Action => "action",
+      NWScriptType::Command => "command",
+      NWScriptType::Effect => "effect",
+      NWScriptType::Event => "event",
+      NWScriptType::ItemProperty => "itemproperty",
+      NWScriptType::Location => "location",
+      NWScriptType::Object => "object",
+      NWScriptType::Player => "player",
+      NWScriptType::Resource => "resource",
+      NWScriptType::Talent => "talent",
+      NWScriptType::Vector => "vector",
+      NWScriptType::Array(ref t) => return write!(f, "{}[]", t),
+      NWScriptType::Ref(ref t) => return write!(f, "ref {}", t)
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// A constant value or argument default from a definitions file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+  Int(i32),
+  Float(f32),
+  String(String),
+  ResRef(String),
+  Vector(f32, f32, f32),
+  Object(u32),
+  Array(Vec<Literal>),
+  Expression(String) // refers to other constants; evaluated by resolve_constants
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+  let (neg, body) = if text.starts_with('-') { (true, &text[1..]) } else { (false, text) };
+  let value = if body.starts_with("0x") || body.starts_with("0X") {
+    i64::from_str_radix(&body[2..], 16).ok()
+  } else if body.chars().all(|c| c.is_ascii_digit()) {
+    body.parse::<i64>().ok()
+  } else {
+    None
+  };
+  value.map(|v| if neg { -v } else { v })
+}
+
+fn parse_float(text: &str) -> Option<f32> {
+  let body = if text.ends_with('f') { &text[..text.len()-1] } else { text };
+  if body.contains('.') { body.parse::<f32>().ok() } else { None }
+}
+
+impl Literal {
+  /// Interpret literal source text as a value of type `t`. Anything that isn't a plain
+  /// literal of that type, such as a constant reference, is kept as an `Expression`.
+  pub fn parse(t: &NWScriptType, text: &str) -> Literal {
+    let text = text.trim();
+    let expression = Literal::Expression(text.to_string());
+    let t = t.base();
+
+    if text.starts_with("R\"") {
+      return Literal::ResRef(text[2..text.len()-1].to_string());
+    }
+    if text.starts_with('"') {
+      let s = text[1..text.len()-1].to_string();
+      return if *t == NWScriptType::Resource { Literal::ResRef(s) } else { Literal::String(s) };
+    }
+    if text.starts_with('[') {
+      let items: Vec<&str> = text[1..text.len()-1].split(',').map(|s| s.trim())
+        .filter(|s| s.len() > 0).collect();
+      if *t == NWScriptType::Vector {
+        let v: Vec<f32> = items.iter().filter_map(|s| parse_float(s)).collect();
+        return if v.len() == 3 && items.len() == 3 {
+          Literal::Vector(v[0], v[1], v[2])
+        } else {
+          expression
+        };
+      }
+      let elem = match *t {
+        NWScriptType::Array(ref e) => (**e).clone(),
+        _ => NWScriptType::Any
+      };
+      return Literal::Array(items.iter().map(|s| Literal::parse(&elem, s)).collect());
+    }
+
+    match (t, parse_int(text), parse_float(text)) {
+      (&NWScriptType::Object, Some(i), _) => Literal::Object(i as u32),
+      (&NWScriptType::Float, Some(i), _) => Literal::Float(i as f32),
+      (&NWScriptType::Float, _, Some(f)) | (&NWScriptType::Any, _, Some(f)) => Literal::Float(f),
+      (&NWScriptType::Int, Some(i), _) | (&NWScriptType::Any, Some(i), _) =>
+        Literal::Int(i as i32),
+      _ => expression
+    }
+  }
+}
+
+impl fmt::Display for Literal {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Literal::Int(i) => write!(f, "{}", i),
+      Literal::Float(v) => {
+        let s = v.to_string();
+        write!(f, "{}{}", s, if s.contains('.') || !v.is_finite() { "" } else { ".0" })
+      },
+      Literal::String(ref s) => write!(f, "\"{}\"", s),
+      Literal::ResRef(ref s) => write!(f, "R\"{}\"", s),
+      Literal::Vector(x, y, z) =>
+        write!(f, "[{}, {}, {}]", Literal::Float(x), Literal::Float(y), Literal::Float(z)),
+      Literal::Object(o) => write!(f, "{:#X}", o),
+      Literal::Array(ref items) => {
+        let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+        write!(f, "[{}]", items.join(", "))
+      },
+      Literal::Expression(ref e) => write!(f, "{}", e)
+    }
+  }
+}
+
+#[cfg(test)]
+mod types_tests {
+  use nwscript;
+  use super::{Literal, NWScriptType};
+
+  #[test]
+  fn typed_literals() {
+    let c = nwscript::constant("object OBJECT_INVALID = 0x7F000000;").unwrap();
+    assert_eq!(c.value, Literal::Object(0x7F000000));
+    let c = nwscript::constant("vector V = [0.0, 1.5f, -2.0];").unwrap();
+    assert_eq!(c.value, Literal::Vector(0.0, 1.5, -2.0));
+    let c = nwscript::constant("float F = 1;").unwrap();
+    assert_eq!(c.value, Literal::Float(1.0));
+    let c = nwscript::constant("resource R = \"nw_it_gold\";").unwrap();
+    assert_eq!(c.value, Literal::ResRef("nw_it_gold".to_string()));
+
+    let r = nwscript::function("void F(ref int[] a, int n = A + 1) = 7;").unwrap();
+    assert_eq!(r.args[0].nwtype,
+               NWScriptType::Ref(Box::new(NWScriptType::Array(Box::new(NWScriptType::Int)))));
+    assert_eq!(r.args[0].nwtype.to_string(), "ref int[]");
+    assert_eq!(r.args[1].default_value, Some(Literal::Expression("A + 1".to_string())));
+  }
+}
@@ -0,0 +1,173 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
+
+use super::Routine;
+use assemble::{assemble, AssemblyError};
+use disassemble::{disassemble, disassemble_op, format_instruction, DisassemblyError,
+                  DisassemblyOptions, HEADER_BYTES};
+use opcodes::Opcode;
+
+
+/// The first byte where a reassembled script stops matching the original, with the
+/// instruction that covers it on each side.
+#[derive(Debug)]
+pub struct Difference {
+  pub offset: usize,
+  pub original: String,
+  pub reassembled: String
+}
+
+impl fmt::Display for Difference {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    try!(writeln!(f, "reassembled output differs at byte {:#010X}", self.offset));
+    try!(writeln!(f, "  original:    {}", self.original));
+    write!(f, "  reassembled: {}", self.reassembled)
+  }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+  Disassembly(DisassemblyError),
+  Reassembly(AssemblyError), // ox couldn't read back its own listing
+  Mismatch(Difference)
+}
+
+impl fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      VerifyError::Disassembly(ref e) => write!(f, "disassembly failed: {}", e),
+      VerifyError::Reassembly(ref e) => write!(f, "reassembly failed: {}", e),
+      VerifyError::Mismatch(ref d) => write!(f, "{}", d)
+    }
+  }
+}
+
+impl Error for VerifyError {
+  fn description(&self) -> &str {
+    "verification failed"
+  }
+}
+
+// Decode instructions from the start of the code up to the one covering offset
+fn describe(data: &[u8],
+            offset: usize,
+            opcodes: &[Option<Opcode>],
+            routines: &HashMap<u16, Routine>,
+            options: &DisassemblyOptions) -> String {
+  if offset >= data.len() {
+    return "<end of file>".to_string();
+  }
+  if offset < HEADER_BYTES {
+    return format!("header \"{}\"", String::from_utf8_lossy(&data[..HEADER_BYTES]));
+  }
+
+  let mut rdr = Cursor::new(&data[HEADER_BYTES..]);
+  let mut pos = HEADER_BYTES;
+  loop {
+    let c = match disassemble_op(&mut rdr, opcodes, pos) {
+      Ok(c) => c,
+      Err(e) => return format!("<undecodable: {}>", e)
+    };
+    if offset < pos + c.bytes_read {
+      return match format_instruction(&c, opcodes, routines, options.engine, options.encoding) {
+        Ok(s) => format!("{:08X}: {}", pos, s),
+        Err(e) => format!("<undecodable: {}>", e)
+      };
+    }
+    pos += c.bytes_read;
+  }
+}
+
+/// Disassemble `data` in memory, assemble the listing again, and check that the result is
+/// byte-for-byte identical to the original.
+pub fn verify(data: &[u8],
+              opcodes: &[Option<Opcode>],
+              routines: &HashMap<u16, Routine>,
+              options: &DisassemblyOptions) -> Result<(), VerifyError> {
+  // objdump-style listings can't be reassembled
+  let options = DisassemblyOptions { listing: false, ..options.clone() };
+
+  let mut listing = vec!();
+  try!(disassemble(&mut Cursor::new(data), &mut listing, opcodes, routines, &options)
+       .map_err(VerifyError::Disassembly));
+
+  let mut rebuilt = vec!();
+  try!(assemble(Cursor::new(listing), &mut rebuilt, opcodes, Some(routines), options.engine,
+                options.encoding)
+       .map_err(VerifyError::Reassembly));
+
+  let offset = match data.iter().zip(rebuilt.iter()).position(|(a, b)| a != b) {
+    Some(n) => n,
+    None if data.len() == rebuilt.len() => return Ok(()),
+    None => cmp::min(data.len(), rebuilt.len())
+  };
+
+  Err(VerifyError::Mismatch(Difference {
+    offset: offset,
+    original: describe(data, offset, opcodes, routines, &options),
+    reassembled: describe(&rebuilt, offset, opcodes, routines, &options)
+  }))
+}
+
+#[cfg(test)]
+mod verify_tests {
+  use std::collections::HashMap;
+  use disassemble::DisassemblyOptions;
+  use opcodes::{get_opcodes, get_nwtypes, Operand, OpcodeE};
+  use super::verify;
+
+  // One instruction for every opcode and legal type in the table, with dummy operands
+  fn every_opcode() -> Vec<u8> {
+    let nwtypes = get_nwtypes();
+    let mut code = vec!();
+    for op in get_opcodes().iter().filter_map(|o| o.as_ref()) {
+      if op.code == OpcodeE::T {
+        continue;
+      }
+      // types the disassembler refuses to print can't round trip
+      for t in op.types.as_ref().unwrap().iter()
+        .filter(|t| nwtypes.get(**t as usize).map_or(false, |t| t.is_some())) {
+        code.push(op.code as u8);
+        code.push(*t);
+        let args = op.args.as_ref().and_then(|a| a.get(t));
+        for (n, arg) in args.into_iter().flat_map(|a| a.iter()).enumerate() {
+          match *arg {
+            Operand::Size(2) if args.unwrap().len() > n + 1 => code.extend(b"\x00\x02"),
+            Operand::String => code.extend(b"ok"),
+            Operand::Routine(sz) | Operand::Object(sz) | Operand::Size(sz) |
+            Operand::Offset(sz) | Operand::Integer(sz) | Operand::Float(sz) |
+            Operand::ArgCount(sz) => code.extend(vec![0 as u8; sz])
+          }
+        }
+      }
+    }
+    let size = 8 + 5 + code.len() as u32;
+    let mut data = b"NCS V1.0\x42".to_vec();
+    data.extend(vec!((size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8));
+    data.extend(code);
+    data
+  }
+
+  #[test]
+  fn every_opcode_round_trips() {
+    let data = every_opcode();
+    let opcodes = get_opcodes();
+    for labels in [false, true].iter() {
+      let options = DisassemblyOptions { labels: *labels, ..Default::default() };
+      match verify(&data, &opcodes, &HashMap::new(), &options) {
+        Ok(_) => (),
+        Err(e) => panic!("{}", e)
+      }
+    }
+  }
+
+  #[test]
+  fn trailing_bytes() {
+    let mut data = every_opcode();
+    data.extend(b"\x20\x00");
+    assert!(verify(&data, &get_opcodes(), &HashMap::new(), &Default::default()).is_err());
+  }
+}
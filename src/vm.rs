@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use super::{NWScriptType, Routine};
+use cfg::state_entry;
+use disassemble::jump_target;
+use io_utils::{bytes_to_float, bytes_to_int, bytes_to_uint, decode_string, Encoding};
+use opcodes::{OpPayload, OpcodeE, Operand};
+
+
+pub const OBJECT_INVALID: u32 = 0x7F000000;
+
+/// A value on the VM stack, or passed to and from an engine routine. Each stack cell holds
+/// four bytes, so vectors take three cells as floats and never appear on the stack whole, and
+/// actions are only ever routine arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Void,
+  Int(i32),
+  Float(f32),
+  String(String),
+  Object(u32),
+  Structure(u8, u32), // engine structure type code, and a handle the host gave it
+  Vector(f32, f32, f32),
+  Action(State)
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Value::Void => write!(f, "void"),
+      Value::Int(n) => write!(f, "{}", n),
+      Value::Float(n) => write!(f, "{:?}", n),
+      Value::String(ref s) => write!(f, "{:?}", s),
+      Value::Object(n) => write!(f, "object {:#X}", n),
+      Value::Structure(t, n) => write!(f, "structure {:#X} {:#X}", t, n),
+      Value::Vector(x, y, z) => write!(f, "[{:?}, {:?}, {:?}]", x, y, z),
+      Value::Action(ref s) => write!(f, "action {:08X}", s.resume)
+    }
+  }
+}
+
+/// The code and stack saved by STORE_STATE, which engine routines like `DelayCommand` take as
+/// an action argument and can run later with `Vm::run_state`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+  pub resume: usize,
+  pub globals: Vec<Value>, // the cells below BP
+  pub locals: Vec<Value> // the cells on top of the stack
+}
+
+/// The game side of the VM: everything `ACTION` calls.
+pub trait Host {
+  /// Run an engine routine. Arguments come first argument first; return `Value::Void` from
+  /// void routines.
+  fn action(&mut self, routine: &Routine, args: Vec<Value>) -> Result<Value, String>;
+}
+
+/// Why the VM stopped, and the instruction it stopped at.
+#[derive(Debug)]
+pub struct VmError {
+  pub offset: usize,
+  pub message: String
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:08X}: {}", self.offset, self.message)
+  }
+}
+
+impl Error for VmError {
+  fn description(&self) -> &str {
+    "script failed"
+  }
+}
+
+/// Executes decoded NCS instructions, as returned by `read_ops`.
+pub struct Vm<'a, 'b: 'a> {
+  ops: &'a [OpPayload<'b>],
+  index: HashMap<usize, usize>,
+  routines: &'a HashMap<u16, Routine>,
+  encoding: Encoding,
+  pub stack: Vec<Value>,
+  pub bp: usize, // in bytes, like SP
+  pub pc: usize,
+  pub calls: Vec<usize>, // return addresses
+  pub saved: Option<State>, // from the last STORE_STATE, waiting for an ACTION to take it
+  pub halted: bool
+}
+
+impl<'a, 'b> Vm<'a, 'b> {
+  pub fn new(ops: &'a [OpPayload<'b>],
+             routines: &'a HashMap<u16, Routine>,
+             encoding: Encoding) -> Vm<'a, 'b> {
+    let start = ops.iter().find(|o| o.op.code != OpcodeE::T).map_or(0, |o| o.offset);
+    Vm {
+      ops: ops,
+      index: ops.iter().enumerate().map(|(n, o)| (o.offset, n)).collect(),
+      routines: routines,
+      encoding: encoding,
+      stack: vec!(),
+      bp: 0,
+      pc: start,
+      calls: vec!(),
+      saved: None,
+      halted: ops.iter().all(|o| o.op.code == OpcodeE::T)
+    }
+  }
+
+  /// The stack pointer in bytes.
+  pub fn sp(&self) -> usize {
+    self.stack.len() * 4
+  }
+
+  /// The instruction about to run.
+  pub fn current(&self) -> Option<&'a OpPayload<'b>> {
+    let ops = self.ops;
+    self.index.get(&self.pc).map(|n| &ops[*n])
+  }
+
+  /// Run until the script returns from its entry point.
+  pub fn run(&mut self, host: &mut Host) -> Result<(), VmError> {
+    while !self.halted {
+      try!(self.step(host));
+    }
+    Ok(())
+  }
+
+  /// Run code saved by STORE_STATE on its own copy of the saved stack, as the engine does when
+  /// a delayed or assigned action fires.
+  pub fn run_state(&mut self, state: &State, host: &mut Host) -> Result<(), VmError> {
+    self.stack = state.globals.iter().chain(state.locals.iter()).cloned().collect();
+    self.bp = state.globals.len() * 4;
+    self.pc = state.resume;
+    self.calls.clear();
+    self.halted = false;
+    self.run(host)
+  }
+
+  /// Run one instruction. If it fails, `pc` stays at that instruction, but whatever it popped
+  /// before failing stays popped, so stepping again won't retry it from a clean state.
+  pub fn step(&mut self, host: &mut Host) -> Result<(), VmError> {
+    let op = match self.current() {
+      Some(op) => op,
+      None => return Err(VmError { offset: self.pc,
+                                   message: "no instruction starts here".to_string() })
+    };
+    self.execute(op, host).map_err(|m| {
+      self.pc = op.offset;
+      VmError { offset: op.offset, message: m }
+    })
+  }
+
+  // Index of the cell `offset` bytes from a base cell, with room for `cells` cells from there
+  fn index(&self, base: usize, offset: i32, cells: usize) -> Result<usize, String> {
+    if offset % 4 != 0 {
+      return Err(format!("offset {} isn't a whole number of cells", offset));
+    }
+    let i = base as i64 + (offset / 4) as i64;
+    if i < 0 || i as usize + cells > self.stack.len() {
+      return Err(format!("offset {} is outside the stack", offset));
+    }
+    Ok(i as usize)
+  }
+
+  fn pop(&mut self) -> Result<Value, String> {
+    self.stack.pop().ok_or("stack underflow".to_string())
+  }
+
+  fn pop_int(&mut self) -> Result<i32, String> {
+    match try!(self.pop()) {
+      Value::Int(n) => Ok(n),
+      v => Err(format!("expected an int, found {}", v))
+    }
+  }
+
+  fn pop_float(&mut self) -> Result<f32, String> {
+    match try!(self.pop()) {
+      Value::Float(n) => Ok(n),
+      v => Err(format!("expected a float, found {}", v))
+    }
+  }
+
+  fn pop_string(&mut self) -> Result<String, String> {
+    match try!(self.pop()) {
+      Value::String(s) => Ok(s),
+      v => Err(format!("expected a string, found {}", v))
+    }
+  }
+
+  fn pop_vector(&mut self) -> Result<(f32, f32, f32), String> {
+    let z = try!(self.pop_float());
+    let y = try!(self.pop_float());
+    let x = try!(self.pop_float());
+    Ok((x, y, z))
+  }
+
+  fn push_value(&mut self, value: Value) {
+    match value {
+      Value::Void => (),
+      Value::Vector(x, y, z) => self.stack.extend(vec!(Value::Float(x), Value::Float(y),
+                                                       Value::Float(z))),
+      v => self.stack.push(v)
+    }
+  }
+
+  // Pop two operands of a binary instruction and push the result
+  fn binary(&mut self, code: OpcodeE, t: u8) -> Result<(), String> {
+    let result = match t {
+      0x20 => {
+        let b = try!(self.pop_int());
+        let a = try!(self.pop_int());
+        Value::Int(match code {
+          OpcodeE::LOGANDII => (a != 0 && b != 0) as i32,
+          OpcodeE::LOGORII => (a != 0 || b != 0) as i32,
+          OpcodeE::INCORII => a | b,
+          OpcodeE::EXCORII => a ^ b,
+          OpcodeE::BOOLANDII => a & b,
+          OpcodeE::GEQ => (a >= b) as i32,
+          OpcodeE::GT => (a > b) as i32,
+          OpcodeE::LT => (a < b) as i32,
+          OpcodeE::LEQ => (a <= b) as i32,
+          OpcodeE::SHLEFTII => a.wrapping_shl(b as u32),
+          OpcodeE::SHRIGHTII => a.wrapping_shr(b as u32),
+          OpcodeE::USHRIGHTII => (a as u32).wrapping_shr(b as u32) as i32,
+          OpcodeE::ADD => a.wrapping_add(b),
+          OpcodeE::SUB => a.wrapping_sub(b),
+          OpcodeE::MUL => a.wrapping_mul(b),
+          OpcodeE::DIV | OpcodeE::MODII if b == 0 => return Err("division by zero".to_string()),
+          OpcodeE::DIV => a.wrapping_div(b),
+          OpcodeE::MODII => a.wrapping_rem(b),
+          _ => return Err(format!("{:?} doesn't take two ints", code))
+        })
+      },
+      0x21 | 0x25 | 0x26 => {
+        let b = if t == 0x26 { try!(self.pop_int()) as f32 } else { try!(self.pop_float()) };
+        let a = if t == 0x25 { try!(self.pop_int()) as f32 } else { try!(self.pop_float()) };
+        match code {
+          OpcodeE::GEQ => Value::Int((a >= b) as i32),
+          OpcodeE::GT => Value::Int((a > b) as i32),
+          OpcodeE::LT => Value::Int((a < b) as i32),
+          OpcodeE::LEQ => Value::Int((a <= b) as i32),
+          OpcodeE::ADD => Value::Float(a + b),
+          OpcodeE::SUB => Value::Float(a - b),
+          OpcodeE::MUL => Value::Float(a * b),
+          OpcodeE::DIV if b == 0.0 => return Err("division by zero".to_string()),
+          OpcodeE::DIV => Value::Float(a / b),
+          _ => return Err(format!("{:?} doesn't take floats", code))
+        }
+      },
+      0x23 if code == OpcodeE::ADD => {
+        let b = try!(self.pop_string());
+        let a = try!(self.pop_string());
+        Value::String(a + &b)
+      },
+      0x3A => {
+        let (bx, by, bz) = try!(self.pop_vector());
+        let (ax, ay, az) = try!(self.pop_vector());
+        match code {
+          OpcodeE::ADD => Value::Vector(ax + bx, ay + by, az + bz),
+          OpcodeE::SUB => Value::Vector(ax - bx, ay - by, az - bz),
+          _ => return Err(format!("{:?} doesn't take two vectors", code))
+        }
+      },
+      0x3B => {
+        let b = try!(self.pop_float());
+        let (x, y, z) = try!(self.pop_vector());
+        match code {
+          OpcodeE::MUL => Value::Vector(x * b, y * b, z * b),
+          OpcodeE::DIV if b == 0.0 => return Err("division by zero".to_string()),
+          OpcodeE::DIV => Value::Vector(x / b, y / b, z / b),
+          _ => return Err(format!("{:?} doesn't take a vector and a float", code))
+        }
+      },
+      0x3C if code == OpcodeE::MUL => {
+        let (x, y, z) = try!(self.pop_vector());
+        let a = try!(self.pop_float());
+        Value::Vector(a * x, a * y, a * z)
+      },
+      t => return Err(format!("{:?} doesn't take type {:#04X}", code, t))
+    };
+    self.push_value(result);
+    Ok(())
+  }
+
+  // Compare the top two runs of `cells` cells
+  fn equal(&mut self, cells: usize) -> Result<bool, String> {
+    if cells * 2 > self.stack.len() {
+      return Err("stack underflow".to_string());
+    }
+    let at = self.stack.len() - cells * 2;
+    let b = self.stack.split_off(at + cells);
+    let a = self.stack.split_off(at);
+    Ok(a == b)
+  }
+
+  fn action(&mut self, code: u16, count: usize, host: &mut Host) -> Result<(), String> {
+    let routine = match self.routines.get(&code) {
+      Some(r) => r,
+      None => return Err(format!("unknown routine {}", code))
+    };
+    if count > routine.args.len() {
+      return Err(format!("{} takes {} arguments but is passed {}", routine.name,
+                         routine.args.len(), count));
+    }
+
+    let mut args = vec!();
+    for arg in routine.args[..count].iter() {
+      args.push(match *arg.nwtype.base() {
+        NWScriptType::Int => Value::Int(try!(self.pop_int())),
+        NWScriptType::Float => Value::Float(try!(self.pop_float())),
+        NWScriptType::String => Value::String(try!(self.pop_string())),
+        NWScriptType::Vector => {
+          let (x, y, z) = try!(self.pop_vector());
+          Value::Vector(x, y, z)
+        },
+        NWScriptType::Action => match self.saved.take() {
+          Some(s) => Value::Action(s),
+          None => return Err(format!("no STORE_STATE for {}'s action argument", routine.name))
+        },
+        _ => try!(self.pop())
+      });
+    }
+
+    let result = try!(host.action(routine, args));
+    match (&routine.return_type, &result) {
+      (&NWScriptType::Void, &Value::Void) => (),
+      (&NWScriptType::Void, _) | (_, &Value::Void) =>
+        return Err(format!("{} should return {} but returned {}", routine.name,
+                           routine.return_type, result)),
+      _ => ()
+    }
+    self.push_value(result);
+    Ok(())
+  }
+
+  fn execute(&mut self, op: &OpPayload, host: &mut Host) -> Result<(), String> {
+    let args = op.numbers();
+    let arg = |n: usize| args.get(n).cloned().unwrap_or(0);
+    let next = op.offset + op.bytes_read;
+    let sp = self.stack.len();
+    let bp = self.bp / 4;
+    self.pc = next;
+
+    match op.op.code {
+      OpcodeE::CPDOWNSP | OpcodeE::CPDOWNBP => {
+        let cells = arg(1) as usize / 4;
+        let base = if op.op.code == OpcodeE::CPDOWNSP { sp } else { bp };
+        let to = try!(self.index(base, arg(0), cells));
+        let from = try!(self.index(sp, -arg(1), cells));
+        for i in 0..cells {
+          self.stack[to + i] = self.stack[from + i].clone();
+        }
+      },
+      OpcodeE::CPTOPSP | OpcodeE::CPTOPBP => {
+        let cells = arg(1) as usize / 4;
+        let base = if op.op.code == OpcodeE::CPTOPSP { sp } else { bp };
+        let from = try!(self.index(base, arg(0), cells));
+        let copy = self.stack[from..from + cells].to_vec();
+        self.stack.extend(copy);
+      },
+      OpcodeE::RSADD => {
+        let value = match op._type.unwrap_or(0) {
+          0x03 => Value::Int(0),
+          0x04 => Value::Float(0.0),
+          0x05 => Value::String(String::new()),
+          0x06 => Value::Object(OBJECT_INVALID),
+          t => Value::Structure(t, 0)
+        };
+        self.stack.push(value);
+      },
+      OpcodeE::CONST => {
+        let value = op.args.iter().filter_map(|&(a, ref bytes)| match *a {
+          Operand::Integer(..) => bytes_to_int(bytes).ok().map(Value::Int),
+          Operand::Float(..) => bytes_to_float(bytes).ok().map(Value::Float),
+          Operand::Object(..) => bytes_to_uint(bytes).ok().map(Value::Object),
+          Operand::String => Some(Value::String(decode_string(bytes, self.encoding))),
+          _ => None
+        }).next();
+        self.stack.push(try!(value.ok_or("constant has no value".to_string())));
+      },
+      OpcodeE::ACTION => try!(self.action(arg(0) as u16, arg(1) as usize, host)),
+      OpcodeE::EQUAL | OpcodeE::NEQUAL => {
+        let cells = if op._type == Some(0x24) { arg(0) as usize / 4 } else { 1 };
+        let same = try!(self.equal(cells));
+        self.stack.push(Value::Int((same == (op.op.code == OpcodeE::EQUAL)) as i32));
+      },
+      OpcodeE::LOGANDII | OpcodeE::LOGORII | OpcodeE::INCORII | OpcodeE::EXCORII |
+      OpcodeE::BOOLANDII | OpcodeE::GEQ | OpcodeE::GT | OpcodeE::LT | OpcodeE::LEQ |
+      OpcodeE::SHLEFTII | OpcodeE::SHRIGHTII | OpcodeE::USHRIGHTII | OpcodeE::ADD |
+      OpcodeE::SUB | OpcodeE::MUL | OpcodeE::DIV | OpcodeE::MODII =>
+        try!(self.binary(op.op.code, op._type.unwrap_or(0))),
+      OpcodeE::NEG => {
+        let value = match try!(self.pop()) {
+          Value::Int(n) => Value::Int(n.wrapping_neg()),
+          Value::Float(n) => Value::Float(-n),
+          v => return Err(format!("can't negate {}", v))
+        };
+        self.stack.push(value);
+      },
+      OpcodeE::COMPI => {
+        let n = try!(self.pop_int());
+        self.stack.push(Value::Int(!n));
+      },
+      OpcodeE::NOTI => {
+        let n = try!(self.pop_int());
+        self.stack.push(Value::Int((n == 0) as i32));
+      },
+      OpcodeE::MOVSP => {
+        let to = try!(self.index(sp, arg(0), 0));
+        self.stack.truncate(to);
+      },
+      OpcodeE::JMP => self.pc = jump_target(op).unwrap_or(next),
+      OpcodeE::JSR => {
+        self.calls.push(next);
+        self.pc = jump_target(op).unwrap_or(next);
+      },
+      OpcodeE::JZ | OpcodeE::JNZ => {
+        let n = try!(self.pop_int());
+        if (n == 0) == (op.op.code == OpcodeE::JZ) {
+          self.pc = jump_target(op).unwrap_or(next);
+        }
+      },
+      OpcodeE::RETN => match self.calls.pop() {
+        Some(ret) => self.pc = ret,
+        None => {
+          self.pc = op.offset;
+          self.halted = true;
+        }
+      },
+      OpcodeE::DESTRUCT => {
+        let from = try!(self.index(sp, -arg(0), 0));
+        let keep = try!(self.index(from, arg(1), arg(2) as usize / 4));
+        let kept = self.stack[keep..keep + arg(2) as usize / 4].to_vec();
+        self.stack.truncate(from);
+        self.stack.extend(kept);
+      },
+      OpcodeE::DECISP | OpcodeE::INCISP | OpcodeE::DECIBP | OpcodeE::INCIBP => {
+        let base = match op.op.code { OpcodeE::DECISP | OpcodeE::INCISP => sp, _ => bp };
+        let i = try!(self.index(base, arg(0), 1));
+        let step = match op.op.code { OpcodeE::DECISP | OpcodeE::DECIBP => -1, _ => 1 };
+        match self.stack[i] {
+          Value::Int(ref mut n) => *n = n.wrapping_add(step),
+          ref v => return Err(format!("expected an int, found {}", v))
+        }
+      },
+      OpcodeE::SAVEBP => {
+        self.stack.push(Value::Int(self.bp as i32));
+        self.bp = self.stack.len() * 4;
+      },
+      OpcodeE::RESTOREBP => self.bp = try!(self.pop_int()) as usize,
+      OpcodeE::STORE_STATE => {
+        let n = self.index[&op.offset];
+        let resume = try!(state_entry(self.ops, n).ok_or("STORE_STATE isn't followed by a JMP"
+                                                          .to_string()));
+        let below = |n: i32| n.checked_neg().ok_or(format!("offset {} is outside the stack",
+                                                          -(n as i64)));
+        let globals = try!(self.index(bp, try!(below(arg(0))), arg(0) as usize / 4));
+        let locals = try!(self.index(sp, try!(below(arg(1))), 0));
+        self.saved = Some(State { resume: resume,
+                                  globals: self.stack[globals..bp].to_vec(),
+                                  locals: self.stack[locals..].to_vec() });
+      },
+      OpcodeE::NOP | OpcodeE::T => (),
+      code => return Err(format!("{:?} isn't supported", code))
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod vm_tests {
+  use std::collections::HashMap;
+  use std::io::Cursor;
+  use disassemble::read_ops;
+  use io_utils::Encoding;
+  use opcodes::get_opcodes;
+  use types::NWScriptType;
+  use {Routine, RoutineArg};
+  use super::{Host, Value, Vm};
+
+  struct Recorder {
+    calls: Vec<(String, Vec<Value>)>
+  }
+
+  impl Host for Recorder {
+    fn action(&mut self, routine: &Routine, args: Vec<Value>) -> Result<Value, String> {
+      self.calls.push((routine.name.clone(), args));
+      Ok(Value::Void)
+    }
+  }
+
+  #[test]
+  fn runs_script() {
+    // 0D RSADDI, 0F JSR +8, 15 RETN, 17 CONSTI 2, 1D CONSTI 3, 23 MULII, 25 CPDOWNSP -8 4,
+    // 2D MOVSP -4, 33 RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x35\x02\x03\x1E\x00\x00\x00\x00\x08\x20\x00\
+                 \x04\x03\x00\x00\x00\x02\x04\x03\x00\x00\x00\x03\x16\x20\
+                 \x01\x01\xFF\xFF\xFF\xF8\x00\x04\x1B\x00\xFF\xFF\xFF\xFC\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let routines = HashMap::new();
+    let mut host = Recorder { calls: vec!() };
+    let mut vm = Vm::new(&ops, &routines, Encoding::Utf8);
+    vm.run(&mut host).unwrap();
+    assert_eq!(vm.stack, vec!(Value::Int(6)));
+  }
+
+  #[test]
+  fn actions_and_states() {
+    // 0D STORE_STATE 0 0, 17 JMP +0x13, 1D CONSTS "hi", 23 ACTION 0 1, 28 RETN,
+    // 2A ACTION 1 1, 2F RETN
+    let data = b"NCS V1.0\x42\x00\x00\x00\x31\x2C\x10\x00\x00\x00\x00\x00\x00\x00\x00\
+                 \x1D\x00\x00\x00\x00\x13\x04\x05\x00\x02hi\x05\x00\x00\x00\x01\x20\x00\
+                 \x05\x00\x00\x01\x01\x20\x00";
+    let opcodes = get_opcodes();
+    let (_, ops) = read_ops(&mut Cursor::new(&data[..]), &opcodes).unwrap();
+    let arg = |t: NWScriptType| RoutineArg { nwtype: t, name: "a".to_string(),
+                                             default_value: None };
+    let mut routines = HashMap::new();
+    routines.insert(0, Routine { return_type: NWScriptType::Void, name: "Print".to_string(),
+                                 code: 0, args: vec!(arg(NWScriptType::String)) });
+    routines.insert(1, Routine { return_type: NWScriptType::Void, name: "Later".to_string(),
+                                 code: 1, args: vec!(arg(NWScriptType::Action)) });
+    let mut host = Recorder { calls: vec!() };
+    let mut vm = Vm::new(&ops, &routines, Encoding::Utf8);
+    vm.run(&mut host).unwrap();
+
+    let state = match host.calls[0].1[0] {
+      Value::Action(ref s) => s.clone(),
+      ref v => panic!("expected an action, got {}", v)
+    };
+    assert_eq!(state.resume, 0x1D);
+    vm.run_state(&state, &mut host).unwrap();
+    assert_eq!(host.calls[1], ("Print".to_string(), vec!(Value::String("hi".to_string()))));
+  }
+}